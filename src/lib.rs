@@ -3,25 +3,59 @@ extern crate lazy_static;
 extern crate regex;
 
 use regex::Regex;
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
 use std::fmt;
+use std::io::{self, BufRead, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::ops::Range;
+use std::path::Path;
 use std::str::FromStr;
 use std::vec::Vec;
 
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Debug, Clone, Eq, PartialEq)]
 pub struct HostsFile {
     pub lines: Vec<HostsFileLine>,
 }
 
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Debug, Clone)]
 pub struct HostsFileLine {
     is_empty: bool,
     comment: Option<String>,
     ip: Option<String>,
     hosts: Option<Vec<String>>,
+    unknown: Option<String>,
+    // The line exactly as it was read, cleared whenever the line is modified.
+    original: Option<String>,
+    // How the line was terminated in the parsed input; `None` for a final line without a
+    // newline or a line that was not parsed from text.
+    line_ending: Option<LineEnding>,
+    // Standalone comment lines attached by `HostsFile::attach_leading_comments`, each with its
+    // own `original` and ending. The file serializers render them right above this line;
+    // `Display`, string comparison and the length helpers leave them out.
+    leading_comments: Vec<HostsFileLine>,
+}
+
+// Lines compare by content; the formatting retained in `original` is ignored.
+impl PartialEq for HostsFileLine {
+    fn eq(&self, other: &HostsFileLine) -> bool {
+        self.is_empty == other.is_empty
+            && self.comment == other.comment
+            && self.ip == other.ip
+            && self.hosts == other.hosts
+            && self.unknown == other.unknown
+            && self.leading_comments == other.leading_comments
+    }
 }
 
+impl Eq for HostsFileLine {}
+
 impl fmt::Display for HostsFileLine {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(raw) = &self.unknown {
+            return write!(f, "{}", raw);
+        }
         // let out = match self {
         //     HostsFileLine::Empty => "".to_string(),
         //     HostsFileLine::Comment(s) => format!("#{}", s),
@@ -35,15 +69,23 @@ impl fmt::Display for HostsFileLine {
             parts.append(&mut clone);
         }
         parts.push(self.comment.clone());
-        let parts: Vec<String> = parts
-            .iter()
-            .filter(|s| s.is_some())
-            .map(|s| s.clone().unwrap())
-            .collect();
+        let parts: Vec<String> = parts.iter().filter_map(|s| s.clone()).collect();
         write!(f, "{}", parts.join(" "))
     }
 }
 
+impl PartialEq<str> for HostsFileLine {
+    fn eq(&self, other: &str) -> bool {
+        self.to_string().as_str() == other.trim()
+    }
+}
+
+impl PartialEq<&str> for HostsFileLine {
+    fn eq(&self, other: &&str) -> bool {
+        self == *other
+    }
+}
+
 impl FromStr for HostsFileLine {
     type Err = ParseError;
     fn from_str(s: &str) -> Result<HostsFileLine, Self::Err> {
@@ -58,6 +100,10 @@ impl HostsFileLine {
             comment: None,
             ip: None,
             hosts: None,
+            unknown: None,
+            original: None,
+            line_ending: None,
+            leading_comments: vec![],
         }
     }
     pub fn from_comment(c: &str) -> HostsFileLine {
@@ -66,305 +112,4736 @@ impl HostsFileLine {
             comment: Some(c.to_string()),
             ip: None,
             hosts: None,
+            unknown: None,
+            original: None,
+            line_ending: None,
+            leading_comments: vec![],
         }
     }
-    pub fn from_string(line: &str) -> Result<HostsFileLine, ParseError> {
-        let line = line.trim();
-        if line == "" {
-            return Ok(HostsFileLine::from_empty());
-        }
-        lazy_static! {
-            static ref COMMENT_RE: Regex = Regex::new(r"^#.*").unwrap();
+    // A comment line that keeps `raw` as-is, leading indentation included. `raw` must be a
+    // single line whose first non-whitespace character is `#`.
+    pub fn from_comment_raw(raw: &str) -> Result<HostsFileLine, ParseError> {
+        let raw = raw.trim_end_matches(&['\r', '\n'][..]);
+        if !raw.trim_start().starts_with('#') || raw.contains(&['\r', '\n'][..]) {
+            return Err(ParseError::new(ParseErrorKind::InvalidLine));
         }
-        if COMMENT_RE.is_match(line) {
-            return Ok(HostsFileLine::from_comment(line));
+        Ok(HostsFileLine::from_comment(raw))
+    }
+    pub fn from_host(ip: &str, hosts: &[&str]) -> Result<HostsFileLine, ParseError> {
+        let ip = ip.trim();
+        if parse_ip(ip).is_none() {
+            return Err(ParseError::new(ParseErrorKind::InvalidIp));
         }
-        let slices: Vec<String> = line.split_whitespace().map(|s| s.to_string()).collect();
-        let ip: String = slices.first().ok_or(ParseError)?.clone();
-        let hosts: Vec<String> = (&slices[1..])
-            .iter()
-            .take_while(|s| !COMMENT_RE.is_match(s))
-            .map(|h| h.to_string())
-            .collect();
-        if hosts.is_empty() {
-            return Err(ParseError);
+        HostsFileLine::from_checked_ip(ip.to_string(), hosts)
+    }
+    fn from_checked_ip(ip: String, hosts: &[&str]) -> Result<HostsFileLine, ParseError> {
+        if hosts.is_empty() || !hosts.iter().all(|h| is_host_token(h)) {
+            return Err(ParseError::new(ParseErrorKind::InvalidHostname));
         }
-        let comment: String = (&slices[1..])
-            .iter()
-            .skip_while(|s| !COMMENT_RE.is_match(s))
-            .map(|h| h.to_string())
-            .collect::<Vec<String>>()
-            .join(" ");
-        let comment = match comment.as_str() {
-            "" => None,
-            _ => Some(comment.to_string()),
-        };
         Ok(HostsFileLine {
             is_empty: false,
+            comment: None,
             ip: Some(ip),
-            hosts: Some(hosts),
-            comment,
+            hosts: Some(hosts.iter().map(|h| h.to_string()).collect()),
+            unknown: None,
+            original: None,
+            line_ending: None,
+            leading_comments: vec![],
         })
     }
+    // A line the parser could not make sense of in loose mode, kept verbatim.
+    pub fn from_unknown(raw: &str) -> HostsFileLine {
+        HostsFileLine {
+            is_empty: false,
+            comment: None,
+            ip: None,
+            hosts: None,
+            unknown: Some(raw.to_string()),
+            original: None,
+            line_ending: None,
+            leading_comments: vec![],
+        }
+    }
+    pub fn from_string(line: &str) -> Result<HostsFileLine, ParseError> {
+        HostsParser::new().parse_line(line)
+    }
     pub fn ip(&self) -> Option<String> {
         self.ip.clone()
     }
     pub fn hosts(&self) -> Vec<String> {
-        self.hosts.clone().unwrap_or_else(|| vec![])
+        self.hosts.clone().unwrap_or_default()
+    }
+    // The first (canonical) hostname of a host line.
+    pub fn primary_host(&self) -> Option<&str> {
+        self.hosts.as_ref()?.first().map(String::as_str)
     }
     pub fn comment(&self) -> Option<String> {
         self.comment.clone()
     }
+    // The comment text without its leading run of `#` and one following space.
+    pub fn comment_body(&self) -> Option<String> {
+        self.comment.as_ref().map(|c| {
+            let body = c.trim_start().trim_start_matches('#');
+            body.strip_prefix(' ').unwrap_or(body).to_string()
+        })
+    }
     pub fn has_host(&self) -> bool {
         self.ip.is_some()
     }
     pub fn has_comment(&self) -> bool {
         self.comment.is_some()
     }
+    pub fn is_empty(&self) -> bool {
+        self.is_empty
+    }
+    pub fn is_comment(&self) -> bool {
+        !self.has_host() && self.has_comment()
+    }
+    pub fn is_unknown(&self) -> bool {
+        self.unknown.is_some()
+    }
+    pub fn ip_addr(&self) -> Option<IpAddr> {
+        self.ip.as_ref().and_then(|ip| parse_ip(ip))
+    }
+    pub fn set_ip(&mut self, ip: &str) -> Result<(), ParseError> {
+        let ip = ip.trim();
+        if !self.has_host() {
+            return Err(ParseError::new(ParseErrorKind::InvalidLine));
+        }
+        if parse_ip(ip).is_none() {
+            return Err(ParseError::new(ParseErrorKind::InvalidIp));
+        }
+        self.ip = Some(ip.to_string());
+        self.mark_modified();
+        Ok(())
+    }
+    // Appends hostnames to a host line, skipping ones already present (case-insensitive).
+    // Nothing is changed if the line has no address or a name is not a valid token.
+    pub fn add_hosts(&mut self, hosts: &[&str]) -> Result<(), ParseError> {
+        let existing = match self.hosts.as_mut() {
+            Some(existing) if self.ip.is_some() => existing,
+            _ => return Err(ParseError::new(ParseErrorKind::InvalidLine)),
+        };
+        if !hosts.iter().all(|h| is_host_token(h)) {
+            return Err(ParseError::new(ParseErrorKind::InvalidHostname));
+        }
+        let before = existing.len();
+        for host in hosts {
+            if !existing.iter().any(|h| h.eq_ignore_ascii_case(host)) {
+                existing.push(host.to_string());
+            }
+        }
+        if existing.len() != before {
+            self.mark_modified();
+        }
+        Ok(())
+    }
+    // Sets or clears the comment, prefixing `# ` when missing. An empty line given a comment
+    // becomes a comment line and a comment line without one becomes empty; unknown lines are
+    // left as they are.
+    pub fn set_comment(&mut self, comment: Option<&str>) {
+        if self.is_unknown() {
+            return;
+        }
+        self.comment = comment.map(normalize_comment);
+        self.is_empty = !self.has_host() && self.comment.is_none();
+        self.mark_modified();
+    }
+    pub fn with_comment(mut self, comment: &str) -> HostsFileLine {
+        self.set_comment(Some(comment));
+        self
+    }
+    // Length of the serialized line in bytes and in characters.
+    pub fn byte_len(&self) -> usize {
+        self.to_string().len()
+    }
+    pub fn char_len(&self) -> usize {
+        self.to_string().chars().count()
+    }
+    // The line as `HostsFile::serialize_preserving` writes it, attached comments excluded. Only
+    // the last line of a file keeps a missing final newline.
+    fn render_preserving(&self, is_last: bool) -> String {
+        let ending = match self.line_ending {
+            Some(LineEnding::CrLf) => "\r\n",
+            None if is_last && self.original.is_some() => "",
+            _ => "\n",
+        };
+        match &self.original {
+            Some(original) => format!("{}{}", original, ending),
+            None => format!("{}{}", self, ending),
+        }
+    }
+    pub fn leading_comments(&self) -> Vec<String> {
+        self.leading_comments
+            .iter()
+            .filter_map(|c| c.comment.clone())
+            .collect()
+    }
+    pub fn original(&self) -> Option<&str> {
+        self.original.as_deref()
+    }
+    fn mark_modified(&mut self) {
+        self.original = None;
+    }
+    // Whether this host line contains `name` (case-insensitive); false for other lines.
+    pub fn matches_hostname(&self, name: &str) -> bool {
+        self.hosts
+            .iter()
+            .flatten()
+            .any(|h| h.eq_ignore_ascii_case(name))
+    }
+    pub fn host_entry(&self) -> Option<HostsFileHost> {
+        Some(HostsFileHost {
+            ip: self.ip.clone()?,
+            hosts: self.hosts(),
+            comment: self.comment.clone(),
+        })
+    }
+    // Hostnames that are really address literals, usually a mistake.
+    pub fn ip_literal_hosts(&self) -> Vec<String> {
+        self.hosts
+            .iter()
+            .flatten()
+            .filter(|h| parse_ip(h).is_some())
+            .cloned()
+            .collect()
+    }
+    // For a comment line such as `# 127.0.0.1 localhost`, the entry it would hold if
+    // uncommented. The body must start with a valid address; the line itself is unchanged.
+    pub fn as_commented_entry(&self) -> Option<HostsFileHost> {
+        if !self.is_comment() {
+            return None;
+        }
+        HostsParser::new()
+            .validate_ips(true)
+            .parse_line(&self.comment_body()?)
+            .ok()?
+            .host_entry()
+    }
 }
 
-#[derive(Debug, Eq, PartialEq)]
+fn hostname_eq(a: &str, b: &str, case_sensitive: bool) -> bool {
+    if case_sensitive {
+        a == b
+    } else {
+        a.eq_ignore_ascii_case(b)
+    }
+}
+
+// Whether `s` would be read back as a single hostname.
+fn is_host_token(s: &str) -> bool {
+    !s.is_empty() && !s.starts_with('#') && !s.contains(char::is_whitespace)
+}
+
+// Heuristic: a dotted name whose last label looks like a public TLD rather than one of the
+// names conventionally used for local or private networks.
+fn looks_public(hostname: &str) -> bool {
+    const LOCAL_TLDS: &[&str] = &[
+        "localhost",
+        "localdomain",
+        "local",
+        "lan",
+        "home",
+        "internal",
+        "intranet",
+        "corp",
+        "test",
+        "invalid",
+    ];
+    let hostname = hostname.trim_end_matches('.');
+    match hostname.rsplit_once('.') {
+        Some((_, tld)) => {
+            tld.len() >= 2
+                && tld.chars().all(|c| c.is_ascii_alphabetic())
+                && !LOCAL_TLDS.iter().any(|l| tld.eq_ignore_ascii_case(l))
+        }
+        None => false,
+    }
+}
+
+// Prefixes `# ` to comment text that does not already start with `#`.
+fn normalize_comment(c: &str) -> String {
+    if c.starts_with('#') {
+        c.to_string()
+    } else {
+        format!("# {}", c)
+    }
+}
+
+// Keeps the run of `#` as is (`##`/`###` are often used as heading levels) and collapses the
+// whitespace after it to one space; surrounding whitespace is dropped.
+fn normalize_comment_spacing(c: &str) -> String {
+    let c = c.trim();
+    let body = c.trim_start_matches('#');
+    let hashes = &c[..c.len() - body.len()];
+    if body.starts_with(char::is_whitespace) {
+        format!("{} {}", hashes, body.trim_start())
+    } else {
+        c.to_string()
+    }
+}
+
+// Parses an address as written in a hosts file, ignoring any IPv6 zone suffix (`fe80::1%lo0`).
+// A zone must be non-empty and there can only be one.
+fn parse_ip(s: &str) -> Option<IpAddr> {
+    match s.find('%') {
+        Some(i) => {
+            let zone = &s[i + 1..];
+            if zone.is_empty() || zone.contains('%') {
+                return None;
+            }
+            s[..i].parse::<Ipv6Addr>().ok().map(IpAddr::V6)
+        }
+        None => s.parse::<IpAddr>().ok(),
+    }
+}
+
+// Renders an address in its canonical textual form, keeping any IPv6 zone suffix.
+fn canonical_ip(s: &str) -> Option<String> {
+    let ip = parse_ip(s)?;
+    Some(match s.find('%') {
+        Some(i) => format!("{}{}", ip, &s[i..]),
+        None => ip.to_string(),
+    })
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+// Parses `addr/prefix` into the network address and prefix length.
+fn parse_cidr(cidr: &str) -> Option<(IpAddr, u32)> {
+    let mut parts = cidr.trim().splitn(2, '/');
+    let addr = parts.next()?.parse::<IpAddr>().ok()?;
+    let prefix = parts.next()?.parse::<u32>().ok()?;
+    let max = match addr {
+        IpAddr::V4(_) => 32,
+        IpAddr::V6(_) => 128,
+    };
+    if prefix > max {
+        return None;
+    }
+    Some((addr, prefix))
+}
+
+fn cidr_contains(network: IpAddr, prefix: u32, ip: IpAddr) -> bool {
+    fn prefix_matches(a: &[u8], b: &[u8], prefix: u32) -> bool {
+        let full = (prefix / 8) as usize;
+        let rest = prefix % 8;
+        if a[..full] != b[..full] {
+            return false;
+        }
+        rest == 0 || {
+            let mask = 0xffu8 << (8 - rest);
+            a[full] & mask == b[full] & mask
+        }
+    }
+    match (network, ip) {
+        (IpAddr::V4(n), IpAddr::V4(i)) => prefix_matches(&n.octets(), &i.octets(), prefix),
+        (IpAddr::V6(n), IpAddr::V6(i)) => prefix_matches(&n.octets(), &i.octets(), prefix),
+        _ => false,
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
 pub struct HostsFileHost {
     pub ip: String,
     pub hosts: Vec<String>,
     pub comment: Option<String>,
 }
 
-pub struct ParseError;
-
-impl fmt::Display for ParseError {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "Error parsing hosts file")
+impl HostsFileHost {
+    pub fn ip_addr(&self) -> Option<IpAddr> {
+        parse_ip(&self.ip)
+    }
+    pub fn matches_ip(&self, ip: &IpAddr) -> bool {
+        self.ip_addr().as_ref() == Some(ip)
     }
 }
 
-impl fmt::Debug for ParseError {
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum ParseErrorKind {
+    InvalidLine,
+    InvalidIp,
+    InvalidHostname,
+    LimitExceeded,
+    LineTooLong,
+    // A hostname found on more than one host line, see `HostsParser::reject_duplicate_hosts`.
+    DuplicateHostname(String),
+}
+
+impl fmt::Display for ParseErrorKind {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{{ file: {}, line: {} }}", file!(), line!())
+        match self {
+            ParseErrorKind::InvalidLine => write!(f, "invalid line"),
+            ParseErrorKind::InvalidIp => write!(f, "invalid IP address"),
+            ParseErrorKind::InvalidHostname => write!(f, "invalid hostname"),
+            ParseErrorKind::LimitExceeded => write!(f, "input size limit exceeded"),
+            ParseErrorKind::LineTooLong => write!(f, "line too long"),
+            ParseErrorKind::DuplicateHostname(name) => write!(f, "duplicate hostname {}", name),
+        }
     }
 }
 
-impl FromStr for HostsFile {
-    type Err = ParseError;
-    fn from_str(s: &str) -> Result<HostsFile, Self::Err> {
-        HostsFile::from_string(s)
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ParseError {
+    kind: ParseErrorKind,
+    line: Option<usize>,
+}
+
+impl ParseError {
+    fn new(kind: ParseErrorKind) -> ParseError {
+        ParseError { kind, line: None }
+    }
+    fn at_line(mut self, line: usize) -> ParseError {
+        self.line = Some(line);
+        self
+    }
+    pub fn kind(&self) -> &ParseErrorKind {
+        &self.kind
+    }
+    // The 1-based number of the offending line, when the error came from parsing a file.
+    pub fn line(&self) -> Option<usize> {
+        self.line
     }
 }
-impl HostsFile {
-    fn from_string(s: &str) -> Result<HostsFile, ParseError> {
-        let lines: Vec<HostsFileLine> = s
-            .lines()
-            .map(|l| l.parse::<HostsFileLine>())
-            .collect::<Result<Vec<HostsFileLine>, ParseError>>()?;
-        Ok(HostsFile { lines })
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.line {
+            Some(line) => write!(
+                f,
+                "Error parsing hosts file: {} at line {}",
+                self.kind, line
+            ),
+            None => write!(f, "Error parsing hosts file: {}", self.kind),
+        }
     }
-    pub fn serialize(&self) -> String {
-        format!(
-            "{}\n",
-            self.lines
-                .iter()
-                .map(|l| format!("{}", l))
-                .collect::<Vec<String>>()
-                .join("\n")
-        )
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum LineEnding {
+    Lf,
+    CrLf,
+}
+
+// Writes `contents` to a temporary file next to `path`, flushes it to disk and renames it
+// over `path`, so readers see either the old or the new file. Keeps the permissions of `path`.
+fn write_atomic(path: &Path, contents: &str) -> io::Result<()> {
+    let tmp = path.with_file_name(format!(
+        ".{}.tmp",
+        path.file_name().unwrap_or_default().to_string_lossy()
+    ));
+    let written = std::fs::File::create(&tmp).and_then(|mut f| {
+        f.write_all(contents.as_bytes())?;
+        f.set_permissions(std::fs::metadata(path)?.permissions())?;
+        f.sync_all()
+    });
+    let result = written.and_then(|_| std::fs::rename(&tmp, path));
+    if result.is_err() {
+        let _ = std::fs::remove_file(&tmp);
     }
+    result
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+// Lengths of the longest common subsequences of `a` and each prefix of `b`, in one row.
+fn lcs_row<'a, I>(a: I, b: &[&str]) -> Vec<usize>
+where
+    I: Iterator<Item = &'a str>,
+{
+    let mut row = vec![0; b.len() + 1];
+    for x in a {
+        let mut diagonal = 0;
+        for (j, y) in b.iter().enumerate() {
+            let above = row[j + 1];
+            row[j + 1] = if x == *y {
+                diagonal + 1
+            } else {
+                above.max(row[j])
+            };
+            diagonal = above;
+        }
+    }
+    row
+}
 
-    // Parse tests
-    #[test]
-    fn from_empty() {
-        let parsed = HostsFileLine::from_empty();
-        let expected = HostsFileLine {
-            is_empty: true,
-            ip: None,
-            comment: None,
-            hosts: None,
-        };
-        assert_eq!(parsed, expected);
+// Hirschberg's algorithm: appends the index pairs of a longest common subsequence of `a`
+// and `b`, shifted by `offset`, in linear space.
+fn lcs_pairs(a: &[&str], b: &[&str], offset: (usize, usize), pairs: &mut Vec<(usize, usize)>) {
+    if a.is_empty() || b.is_empty() {
+        return;
     }
-    #[test]
-    fn from_comment() {
-        let parsed = HostsFileLine::from_comment("#test");
-        let expected = HostsFileLine {
-            is_empty: false,
-            ip: None,
-            comment: Some("#test".to_string()),
-            hosts: None,
-        };
-        assert_eq!(parsed, expected);
+    if a.len() == 1 {
+        if let Some(j) = b.iter().position(|y| *y == a[0]) {
+            pairs.push((offset.0, offset.1 + j));
+        }
+        return;
     }
+    let mid = a.len() / 2;
+    let forward = lcs_row(a[..mid].iter().copied(), b);
+    let reversed: Vec<&str> = b.iter().rev().copied().collect();
+    let backward = lcs_row(a[mid..].iter().rev().copied(), &reversed);
+    let split = (0..=b.len())
+        .max_by_key(|k| (forward[*k] + backward[b.len() - k], std::cmp::Reverse(*k)))
+        .unwrap_or(0);
+    lcs_pairs(&a[..mid], &b[..split], offset, pairs);
+    lcs_pairs(
+        &a[mid..],
+        &b[split..],
+        (offset.0 + mid, offset.1 + split),
+        pairs,
+    );
+}
 
-    #[test]
-    fn empty_line_from_string() {
-        let parsed = HostsFileLine::from_string("").unwrap();
-        let expected = HostsFileLine::from_empty();
-        assert_eq!(parsed, expected);
+// Whether `line` is a standalone comment reading `marker`, ignoring surrounding whitespace.
+fn is_marker(line: &HostsFileLine, marker: &str) -> bool {
+    line.is_comment() && line.comment.as_deref().map(str::trim) == Some(marker.trim())
+}
+
+// Splits the line terminator off a raw line, as `str::lines` would.
+fn split_line_ending(raw: &str) -> (&str, Option<LineEnding>) {
+    if let Some(line) = raw.strip_suffix("\r\n") {
+        (line, Some(LineEnding::CrLf))
+    } else if let Some(line) = raw.strip_suffix('\n') {
+        (line, Some(LineEnding::Lf))
+    } else {
+        (raw, None)
     }
-    #[test]
-    fn comment_from_string() {
-        let parsed = HostsFileLine::from_string("# comment").unwrap();
-        let expected = HostsFileLine::from_comment("# comment");
-        assert_eq!(parsed, expected);
+}
+
+// Line terminators seen by the parser, from `HostsFile::line_ending_consistency`.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum LineEndingReport {
+    // No line was read with a terminator, e.g. an empty or built file.
+    Unknown,
+    Lf(usize),
+    CrLf(usize),
+    Mixed { lf: usize, crlf: usize },
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum WhitespaceMode {
+    // Any run of spaces or tabs separates fields.
+    Any,
+    // Fields must be separated by spaces; tabs outside comments are an error.
+    SpacesOnly,
+}
+
+#[derive(Debug, Clone)]
+pub struct HostsParser {
+    validate_hostnames: bool,
+    allow_underscores: bool,
+    validate_ips: bool,
+    loose: bool,
+    max_lines: Option<usize>,
+    max_bytes: Option<usize>,
+    max_line_bytes: Option<usize>,
+    line_continuation: bool,
+    reject_duplicate_hosts: bool,
+    field_separator: WhitespaceMode,
+    comment_re: Regex,
+}
+
+impl Default for HostsParser {
+    fn default() -> HostsParser {
+        lazy_static! {
+            static ref COMMENT_RE: Regex = comment_regex(&["#"]);
+        }
+        HostsParser {
+            validate_hostnames: false,
+            allow_underscores: false,
+            validate_ips: false,
+            loose: false,
+            max_lines: None,
+            max_bytes: None,
+            max_line_bytes: None,
+            line_continuation: false,
+            reject_duplicate_hosts: false,
+            field_separator: WhitespaceMode::Any,
+            comment_re: COMMENT_RE.clone(),
+        }
     }
+}
 
-    #[test]
-    fn broken_from_string() {
-        HostsFileLine::from_string("127.0.0.1").expect_err("should fail");
+fn comment_regex(prefixes: &[&str]) -> Regex {
+    let alternatives: Vec<String> = prefixes.iter().map(|p| regex::escape(p)).collect();
+    Regex::new(&format!("^(?:{}).*", alternatives.join("|"))).unwrap()
+}
+
+impl HostsParser {
+    pub fn new() -> HostsParser {
+        HostsParser::default()
     }
-    #[test]
-    fn host_from_string() {
-        let parsed = HostsFileLine::from_string("127.0.0.1 localhost").unwrap();
-        let expected = HostsFileLine {
-            is_empty: false,
-            ip: Some("127.0.0.1".to_string()),
-            hosts: Some(vec!["localhost".to_string()]),
-            comment: None,
+    // Any token starting with one of these prefixes begins a comment (default: `#`). Empty
+    // prefixes would match every line and are ignored; with none left `#` is used.
+    pub fn comment_prefixes(mut self, prefixes: &[&str]) -> HostsParser {
+        let prefixes: Vec<&str> = prefixes.iter().copied().filter(|p| !p.is_empty()).collect();
+        self.comment_re = if prefixes.is_empty() {
+            comment_regex(&["#"])
+        } else {
+            comment_regex(&prefixes)
         };
-        assert_eq!(parsed, expected);
+        self
     }
-    #[test]
-    fn full_from_string() {
-        let parsed = HostsFileLine::from_string("127.0.0.1 localhost  # a comment").unwrap();
-        let expected = HostsFileLine {
-            is_empty: false,
-            ip: Some("127.0.0.1".to_string()),
-            hosts: Some(vec!["localhost".to_string()]),
-            comment: Some("# a comment".to_string()),
-        };
-        assert_eq!(parsed, expected);
+    pub fn validate_hostnames(mut self, validate: bool) -> HostsParser {
+        self.validate_hostnames = validate;
+        self
     }
-    #[test]
-    fn empty_input() {
-        let parsed = HostsFile::from_str("").unwrap();
-        let expected = HostsFile { lines: vec![] };
-        assert_eq!(parsed, expected);
+    // Relaxes hostname validation to accept `_` in labels (`_service._tcp.example.com`).
+    pub fn allow_underscores(mut self, allow: bool) -> HostsParser {
+        self.allow_underscores = allow;
+        self
     }
-    #[test]
-    fn a_comment() {
-        let parsed = HostsFile::from_str("# comment").unwrap();
-        let expected = HostsFile {
-            lines: vec![HostsFileLine::from_comment("# comment")],
-        };
-        assert_eq!(parsed, expected);
+    pub fn validate_ips(mut self, validate: bool) -> HostsParser {
+        self.validate_ips = validate;
+        self
     }
-    #[test]
-    fn two_comments() {
-        let parsed = HostsFile::from_str("# comment1\n## comment2\n").unwrap();
-        let expected = HostsFile {
-            lines: vec![
-                HostsFileLine::from_comment("# comment1"),
-                HostsFileLine::from_comment("## comment2"),
-            ],
-        };
-        assert_eq!(parsed, expected);
+    // Joins a non-comment line ending in `\` with the next one before parsing. Joined lines have no
+    // preserved original text, so they serialize as a single line.
+    pub fn allow_line_continuation(mut self, allow: bool) -> HostsParser {
+        self.line_continuation = allow;
+        self
     }
-    #[test]
-    fn host_with_comments() {
-        let parsed = HostsFile::from_str("127.0.0.1 localhost # comment\n").unwrap();
-        let expected = HostsFile {
-            lines: vec![HostsFileLine {
-                is_empty: false,
-                ip: Some("127.0.0.1".to_string()),
-                hosts: Some(vec!["localhost".to_string()]),
-                comment: Some("# comment".to_string()),
-            }],
-        };
-        assert_eq!(parsed, expected);
+    // Fails with `ParseErrorKind::DuplicateHostname` at the second line listing a hostname
+    // (case-insensitive) that an earlier host line already has.
+    pub fn reject_duplicate_hosts(mut self, reject: bool) -> HostsParser {
+        self.reject_duplicate_hosts = reject;
+        self
     }
-    #[test]
-    fn whitespace() {
-        let parsed = HostsFile::from_str(" # comment1\n \n    127.0.0.1    localhost\n").unwrap();
-        let expected = HostsFile {
-            lines: vec![
+    // In loose mode lines that fail to parse are kept verbatim as unknown lines instead of
+    // failing the whole file. Addresses are always checked in loose mode, so template lines
+    // such as `{{GW}} router` are kept as unknown rather than read as host entries.
+    pub fn loose(mut self, loose: bool) -> HostsParser {
+        self.loose = loose;
+        self
+    }
+    // Limits for untrusted input; exceeding either fails with `ParseErrorKind::LimitExceeded`.
+    pub fn max_lines(mut self, max: usize) -> HostsParser {
+        self.max_lines = Some(max);
+        self
+    }
+    pub fn max_bytes(mut self, max: usize) -> HostsParser {
+        self.max_bytes = Some(max);
+        self
+    }
+    // Lines longer than `max` bytes (excluding the line ending) fail with
+    // `ParseErrorKind::LineTooLong`.
+    pub fn max_line_bytes(mut self, max: usize) -> HostsParser {
+        self.max_line_bytes = Some(max);
+        self
+    }
+    pub fn field_separator(mut self, mode: WhitespaceMode) -> HostsParser {
+        self.field_separator = mode;
+        self
+    }
+    pub fn parse(&self, s: &str) -> Result<HostsFile, ParseError> {
+        if self.max_bytes.map_or(false, |max| s.len() > max) {
+            return Err(ParseError::new(ParseErrorKind::LimitExceeded));
+        }
+        let mut lines: Vec<HostsFileLine> = vec![];
+        // The input line number each parsed line starts on.
+        let mut numbers: Vec<usize> = vec![];
+        // Text joined so far from continued lines, with the number of its first line.
+        let mut pending: Option<(String, usize)> = None;
+        for (i, raw) in s.split_inclusive('\n').enumerate() {
+            if self.max_lines.map_or(false, |max| i >= max) {
+                return Err(ParseError::new(ParseErrorKind::LimitExceeded).at_line(i + 1));
+            }
+            let (line, ending) = split_line_ending(raw);
+            let (line, number) = match pending.take() {
+                Some((mut joined, start)) => {
+                    joined.push(' ');
+                    joined.push_str(line);
+                    (joined, start)
+                }
+                None => (line.to_string(), i + 1),
+            };
+            // A `\` inside a comment, whole-line or inline, never continues the line, so
+            // `# C:\` cannot swallow the entry after it.
+            let in_comment = line
+                .split_whitespace()
+                .any(|token| self.comment_re.is_match(token));
+            if self.line_continuation && !in_comment {
+                if let Some(head) = line.trim_end().strip_suffix('\\') {
+                    pending = Some((head.to_string(), number));
+                    continue;
+                }
+            }
+            let mut parsed = self.parse_line(&line).map_err(|e| e.at_line(number))?;
+            parsed.line_ending = ending;
+            if number != i + 1 {
+                parsed.mark_modified();
+            }
+            lines.push(parsed);
+            numbers.push(number);
+        }
+        if let Some((line, number)) = pending {
+            let mut parsed = self.parse_line(&line).map_err(|e| e.at_line(number))?;
+            parsed.mark_modified();
+            lines.push(parsed);
+            numbers.push(number);
+        }
+        if self.reject_duplicate_hosts {
+            let mut seen: HashSet<String> = HashSet::new();
+            for (line, number) in lines.iter().zip(numbers) {
+                let hosts = line.hosts.iter().flatten();
+                if let Some(name) = hosts
+                    .clone()
+                    .find(|h| seen.contains(&h.to_ascii_lowercase()))
+                {
+                    return Err(
+                        ParseError::new(ParseErrorKind::DuplicateHostname(name.clone()))
+                            .at_line(number),
+                    );
+                }
+                seen.extend(hosts.map(|h| h.to_ascii_lowercase()));
+            }
+        }
+        Ok(HostsFile { lines })
+    }
+    pub fn parse_line(&self, line: &str) -> Result<HostsFileLine, ParseError> {
+        if self.max_line_bytes.map_or(false, |max| line.len() > max) {
+            return Err(ParseError::new(ParseErrorKind::LineTooLong));
+        }
+        let mut parsed = match self.parse_fields(line) {
+            Err(_) if self.loose => HostsFileLine::from_unknown(line),
+            result => result?,
+        };
+        parsed.original = Some(line.to_string());
+        Ok(parsed)
+    }
+    fn parse_fields(&self, line: &str) -> Result<HostsFileLine, ParseError> {
+        let line = line.trim();
+        if line.is_empty() {
+            return Ok(HostsFileLine::from_empty());
+        }
+        if self.comment_re.is_match(line) {
+            return Ok(HostsFileLine::from_comment(line));
+        }
+        // Single pass over the tokens: the address, hostnames up to the first comment token,
+        // then the comment. Lines with hundreds of aliases are common in generated files.
+        let mut tokens = line.split_whitespace();
+        let ip = tokens.next().unwrap_or_default().to_string();
+        if (self.validate_ips || self.loose) && parse_ip(&ip).is_none() {
+            return Err(ParseError::new(ParseErrorKind::InvalidIp));
+        }
+        // Sized from the remaining length rather than a second pass over the tokens: names are
+        // rarely shorter than 8 bytes with their separator, so alias-heavy lines fill the
+        // vector without reallocating.
+        let mut hosts: Vec<String> = Vec::with_capacity((line.len() - ip.len()) / 8 + 1);
+        let mut comment: Option<String> = None;
+        let mut fields_end = line.len();
+        for token in tokens.by_ref() {
+            if self.comment_re.is_match(token) {
+                comment = Some(token.to_string());
+                fields_end = token.as_ptr() as usize - line.as_ptr() as usize;
+                break;
+            }
+            hosts.push(token.to_string());
+        }
+        if self.field_separator == WhitespaceMode::SpacesOnly && line[..fields_end].contains('\t') {
+            return Err(ParseError::new(ParseErrorKind::InvalidLine));
+        }
+        if let Some(comment) = comment.as_mut() {
+            for token in tokens {
+                comment.push(' ');
+                comment.push_str(token);
+            }
+        }
+        if hosts.is_empty() {
+            return Err(ParseError::new(ParseErrorKind::InvalidLine));
+        }
+        if self.validate_hostnames
+            && !hosts
+                .iter()
+                .all(|h| is_valid_hostname(h, self.allow_underscores))
+        {
+            return Err(ParseError::new(ParseErrorKind::InvalidHostname));
+        }
+        Ok(HostsFileLine {
+            is_empty: false,
+            ip: Some(ip),
+            hosts: Some(hosts),
+            comment,
+            unknown: None,
+            original: None,
+            line_ending: None,
+            leading_comments: vec![],
+        })
+    }
+}
+
+// RFC 1123: dot-separated labels of 1-63 alphanumerics or hyphens, not starting or ending
+// with a hyphen, at most 253 characters overall. A single trailing dot is accepted.
+fn is_valid_hostname(name: &str, allow_underscores: bool) -> bool {
+    let name = name.strip_suffix('.').unwrap_or(name);
+    if name.is_empty() || name.len() > 253 {
+        return false;
+    }
+    name.split('.').all(|label| {
+        !label.is_empty()
+            && label.len() <= 63
+            && !label.starts_with('-')
+            && !label.ends_with('-')
+            && label
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || (allow_underscores && c == '_'))
+    })
+}
+
+impl Error for ParseError {}
+
+#[derive(Debug)]
+pub enum HostsError {
+    Io(io::Error),
+    Parse(ParseError),
+}
+
+impl fmt::Display for HostsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HostsError::Io(e) => write!(f, "Error reading hosts file: {}", e),
+            HostsError::Parse(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl Error for HostsError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            HostsError::Io(e) => Some(e),
+            HostsError::Parse(e) => Some(e),
+        }
+    }
+}
+
+impl From<io::Error> for HostsError {
+    fn from(e: io::Error) -> HostsError {
+        HostsError::Io(e)
+    }
+}
+
+impl From<ParseError> for HostsError {
+    fn from(e: ParseError) -> HostsError {
+        HostsError::Parse(e)
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum HostOp {
+    Add { ip: String, hosts: Vec<String> },
+    Remove { hostname: String },
+    SetIp { hostname: String, ip: String },
+    Comment { hostname: String },
+}
+
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct HostsStats {
+    pub total_lines: usize,
+    pub host_entries: usize,
+    pub comment_lines: usize,
+    pub empty_lines: usize,
+    pub unique_hostnames: usize,
+    pub unique_ips: usize,
+    // Hostnames that appear on more than one host line.
+    pub duplicate_hostnames: usize,
+}
+
+impl std::iter::FromIterator<HostsFileLine> for HostsFile {
+    fn from_iter<I: IntoIterator<Item = HostsFileLine>>(iter: I) -> HostsFile {
+        HostsFile {
+            lines: iter.into_iter().collect(),
+        }
+    }
+}
+
+impl IntoIterator for HostsFile {
+    type Item = HostsFileLine;
+    type IntoIter = std::vec::IntoIter<HostsFileLine>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.lines.into_iter()
+    }
+}
+
+impl<'a> IntoIterator for &'a HostsFile {
+    type Item = &'a HostsFileLine;
+    type IntoIter = std::slice::Iter<'a, HostsFileLine>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.lines.iter()
+    }
+}
+
+// Advisory findings from `HostsFile::validate`; `line` is the index into `HostsFile::lines`.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum ValidationIssue {
+    TabInComment { line: usize },
+    // A public-looking name pointed at a loopback address, shadowing the real service.
+    LoopbackShadow { hostname: String, line: usize },
+    // A host line indented or using tabs between fields, as originally written.
+    Indentation { line: usize },
+    // A `0.0.0.0` or `::` (blocklist) entry without a comment explaining it.
+    NullRouteWithComment { line: usize },
+    // A host line that originally ended in whitespace; fixed by `HostsFile::trim_lines`.
+    TrailingWhitespace { line: usize },
+    // A comment longer than `ValidationOptions::max_comment_len` characters.
+    CommentTooLong { line: usize, len: usize },
+}
+
+// Thresholds for `HostsFile::validate_with`.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct ValidationOptions {
+    max_comment_len: usize,
+}
+
+impl Default for ValidationOptions {
+    fn default() -> ValidationOptions {
+        ValidationOptions {
+            max_comment_len: 256,
+        }
+    }
+}
+
+impl ValidationOptions {
+    pub fn new() -> ValidationOptions {
+        ValidationOptions::default()
+    }
+    pub fn max_comment_len(mut self, max: usize) -> ValidationOptions {
+        self.max_comment_len = max;
+        self
+    }
+}
+
+impl FromStr for HostsFile {
+    type Err = ParseError;
+    fn from_str(s: &str) -> Result<HostsFile, Self::Err> {
+        HostsFile::from_string(s)
+    }
+}
+impl HostsFile {
+    fn from_string(s: &str) -> Result<HostsFile, ParseError> {
+        HostsParser::new().parse(s)
+    }
+    // One host line per entry, in order; fails on the first entry with an invalid address.
+    pub fn from_entries(entries: &[HostsFileHost]) -> Result<HostsFile, ParseError> {
+        let mut file = HostsFile { lines: vec![] };
+        for entry in entries {
+            let hosts: Vec<&str> = entry.hosts.iter().map(|h| h.as_str()).collect();
+            file.add_entry(&entry.ip, &hosts, entry.comment.as_deref())?;
+        }
+        Ok(file)
+    }
+    pub fn into_lines(self) -> Vec<HostsFileLine> {
+        self.lines
+    }
+    pub fn parse_ref(input: &str) -> Result<HostsFileRef<'_>, ParseError> {
+        HostsFileRef::parse(input)
+    }
+    pub fn from_reader<R: Read>(mut reader: R) -> Result<HostsFile, HostsError> {
+        let mut s = String::new();
+        reader.read_to_string(&mut s)?;
+        Ok(HostsFile::from_string(&s)?)
+    }
+    // Whether `input` parses, and parsing its serialization gives back an equal file.
+    pub fn roundtrip_ok(input: &str) -> bool {
+        match HostsFile::from_string(input) {
+            Ok(parsed) => {
+                HostsFile::from_string(&parsed.serialize()).map_or(false, |p| p == parsed)
+            }
+            Err(_) => false,
+        }
+    }
+    // Parses `reader` line by line, passing each result to `f` without keeping it. Parse
+    // errors carry their line number and do not stop the scan; read errors do.
+    pub fn visit_lines<R: BufRead, F: FnMut(Result<HostsFileLine, ParseError>)>(
+        mut reader: R,
+        mut f: F,
+    ) -> io::Result<()> {
+        let parser = HostsParser::new();
+        let mut buf = String::new();
+        let mut number = 0;
+        loop {
+            buf.clear();
+            if reader.read_line(&mut buf)? == 0 {
+                return Ok(());
+            }
+            number += 1;
+            let (line, ending) = split_line_ending(&buf);
+            f(parser
+                .parse_line(line)
+                .map(|mut parsed| {
+                    parsed.line_ending = ending;
+                    parsed
+                })
+                .map_err(|e| e.at_line(number)));
+        }
+    }
+    #[cfg(feature = "gzip")]
+    pub fn from_gz_path<P: AsRef<Path>>(p: P) -> Result<HostsFile, HostsError> {
+        let file = std::fs::File::open(p)?;
+        HostsFile::from_reader(flate2::read::GzDecoder::new(file))
+    }
+    // Every line, including the last, is terminated by `\n`, so N blank lines in the
+    // input come back as exactly N blank lines and an empty file stays empty.
+    pub fn serialize(&self) -> String {
+        self.lines
+            .iter()
+            .flat_map(|l| {
+                l.leading_comments
+                    .iter()
+                    .map(|c| format!("{}\n", c))
+                    .chain(std::iter::once(format!("{}\n", l)))
+            })
+            .collect()
+    }
+    // Like `serialize`, but lines that have not been modified since parsing are written
+    // exactly as they were read, including a CRLF ending or a missing final newline. Other
+    // lines are rendered normally, keeping the ending they were read with.
+    pub fn serialize_preserving(&self) -> String {
+        let last = self.lines.len().saturating_sub(1);
+        self.lines
+            .iter()
+            .enumerate()
+            .flat_map(|(i, l)| {
+                l.leading_comments
+                    .iter()
+                    .map(|c| c.render_preserving(false))
+                    .chain(std::iter::once(l.render_preserving(i == last)))
+            })
+            .collect()
+    }
+    pub fn write_preserving<P: AsRef<Path>>(&self, p: P) -> io::Result<()> {
+        std::fs::write(p, self.serialize_preserving())
+    }
+    // A JSON array with one `{"ip", "hosts", "comment"}` object per host line.
+    pub fn to_json(&self) -> String {
+        let entries: Vec<String> = self
+            .lines
+            .iter()
+            .filter_map(|l| l.host_entry())
+            .map(|e| {
+                let hosts: Vec<String> = e.hosts.iter().map(|h| json_string(h)).collect();
+                format!(
+                    "{{\"ip\":{},\"hosts\":[{}],\"comment\":{}}}",
+                    json_string(&e.ip),
+                    hosts.join(","),
+                    e.comment
+                        .as_ref()
+                        .map_or("null".to_string(), |c| json_string(c))
+                )
+            })
+            .collect();
+        format!("[{}]", entries.join(","))
+    }
+    // A unified diff of the serialized files with 3 lines of context around each hunk, or an
+    // empty string when they serialize identically.
+    pub fn diff_text(&self, other: &HostsFile) -> String {
+        let ours = self.serialize();
+        let theirs = other.serialize();
+        let a: Vec<&str> = ours.lines().collect();
+        let b: Vec<&str> = theirs.lines().collect();
+        if a == b {
+            return String::new();
+        }
+        // Matching line pairs: the common prefix and suffix directly, the rest through a
+        // linear-space LCS so large files do not need an a.len() x b.len() table.
+        let prefix = a.iter().zip(&b).take_while(|(x, y)| x == y).count();
+        let suffix = a[prefix..]
+            .iter()
+            .rev()
+            .zip(b[prefix..].iter().rev())
+            .take_while(|(x, y)| x == y)
+            .count();
+        let mut pairs: Vec<(usize, usize)> = (0..prefix).map(|i| (i, i)).collect();
+        lcs_pairs(
+            &a[prefix..a.len() - suffix],
+            &b[prefix..b.len() - suffix],
+            (prefix, prefix),
+            &mut pairs,
+        );
+        pairs.extend((0..suffix).map(|k| (a.len() - suffix + k, b.len() - suffix + k)));
+        // The edit script: ('-', line), ('+', line) or (' ', line) for each output line.
+        let mut ops: Vec<(char, &str)> = Vec::with_capacity(a.len() + b.len());
+        let (mut i, mut j) = (0, 0);
+        for (pi, pj) in pairs.into_iter().chain(std::iter::once((a.len(), b.len()))) {
+            ops.extend(a[i..pi].iter().map(|line| ('-', *line)));
+            ops.extend(b[j..pj].iter().map(|line| ('+', *line)));
+            if pi < a.len() {
+                ops.push((' ', a[pi]));
+            }
+            i = pi + 1;
+            j = pj + 1;
+        }
+        const CONTEXT: usize = 3;
+        // An empty range is written as the line before it, so inserting into an empty file
+        // reads `-0,0`.
+        let range = |before: usize, len: usize| match len {
+            0 => format!("{},0", before),
+            _ => format!("{},{}", before + 1, len),
+        };
+        let changes: Vec<usize> = (0..ops.len()).filter(|k| ops[*k].0 != ' ').collect();
+        let mut out = String::from("--- a\n+++ b\n");
+        // Lines of `a` and `b` before `pos` in the edit script.
+        let (mut pos, mut seen_a, mut seen_b) = (0, 0, 0);
+        let mut k = 0;
+        while k < changes.len() {
+            let start = changes[k].saturating_sub(CONTEXT);
+            // Changes separated by at most 2 * CONTEXT unchanged lines share a hunk.
+            while k + 1 < changes.len() && changes[k + 1] - changes[k] <= 2 * CONTEXT + 1 {
+                k += 1;
+            }
+            let end = (changes[k] + CONTEXT + 1).min(ops.len());
+            for (op, _) in &ops[pos..start] {
+                seen_a += (*op != '+') as usize;
+                seen_b += (*op != '-') as usize;
+            }
+            let hunk = &ops[start..end];
+            let len_a = hunk.iter().filter(|(op, _)| *op != '+').count();
+            let len_b = hunk.iter().filter(|(op, _)| *op != '-').count();
+            out.push_str(&format!(
+                "@@ -{} +{} @@\n",
+                range(seen_a, len_a),
+                range(seen_b, len_b)
+            ));
+            for (op, line) in hunk {
+                out.push_str(&format!("{}{}\n", op, line));
+            }
+            seen_a += len_a;
+            seen_b += len_b;
+            pos = end;
+            k += 1;
+        }
+        out
+    }
+    // One dnsmasq `address=/hostname/ip` directive per mapping.
+    pub fn to_dnsmasq(&self) -> String {
+        self.iter_hosts()
+            .map(|(ip, host)| format!("address=/{}/{}\n", host, ip))
+            .collect()
+    }
+    // (address as written, hostnames) for each host line, in document order.
+    pub fn as_tuples(&self) -> Vec<(String, Vec<String>)> {
+        self.lines
+            .iter()
+            .filter_map(|l| Some((l.ip.clone()?, l.hosts.clone()?)))
+            .collect()
+    }
+    // Only the host lines, single-spaced and without comments.
+    pub fn shortest_serialization(&self) -> String {
+        self.lines
+            .iter()
+            .filter_map(|l| Some((l.ip.as_ref()?, l.hosts.as_ref()?)))
+            .map(|(ip, hosts)| format!("{} {}\n", ip, hosts.join(" ")))
+            .collect()
+    }
+    // Last mapping wins within each address family. Hostnames are matched case-insensitively
+    // and keyed in lowercase, as in `effective_map`.
+    pub fn to_resolver_map(&self) -> HashMap<String, (Option<Ipv4Addr>, Option<Ipv6Addr>)> {
+        let mut map: HashMap<String, (Option<Ipv4Addr>, Option<Ipv6Addr>)> = HashMap::new();
+        for line in &self.lines {
+            let ip = match line.ip_addr() {
+                Some(ip) => ip,
+                None => continue,
+            };
+            for host in line.hosts() {
+                let entry = map.entry(host.to_ascii_lowercase()).or_insert((None, None));
+                match ip {
+                    IpAddr::V4(v4) => entry.0 = Some(v4),
+                    IpAddr::V6(v6) => entry.1 = Some(v6),
+                }
+            }
+        }
+        map
+    }
+    // The answers a typical resolver gives: the first line mapping a hostname wins for each
+    // address family, later mappings in the same family are shadowed. Hostnames are matched
+    // case-insensitively and keyed in lowercase.
+    pub fn effective_map(&self) -> HashMap<String, (Option<Ipv4Addr>, Option<Ipv6Addr>)> {
+        let mut map: HashMap<String, (Option<Ipv4Addr>, Option<Ipv6Addr>)> = HashMap::new();
+        for (ip, host) in self.iter_hosts() {
+            let entry = map.entry(host.to_ascii_lowercase()).or_insert((None, None));
+            match ip {
+                IpAddr::V4(v4) => {
+                    entry.0.get_or_insert(v4);
+                }
+                IpAddr::V6(v6) => {
+                    entry.1.get_or_insert(v6);
+                }
+            }
+        }
+        map
+    }
+    // Whether any comment line (including attached ones) is `marker` or starts with it.
+    pub fn is_managed_by(&self, marker: &str) -> bool {
+        self.lines.iter().any(|l| {
+            l.leading_comments
+                .iter()
+                .filter_map(|c| c.comment.as_ref())
+                .chain(l.comment.iter().filter(|_| l.is_comment()))
+                .any(|c| c.trim().starts_with(marker))
+        })
+    }
+    // The text after `#!` when the file starts with such a marker line (`#!managed-by-tool`).
+    pub fn managed_marker(&self) -> Option<String> {
+        let first = self.lines.first().filter(|l| l.is_comment())?;
+        first
+            .comment
+            .as_ref()?
+            .strip_prefix("#!")
+            .map(|m| m.trim().to_string())
+    }
+    // Indices of comment lines that mention hostname-like tokens (`staging.example.com`) none
+    // of which is an active hostname anymore. Heuristic, meant for review rather than deletion.
+    pub fn find_orphan_comments(&self) -> Vec<usize> {
+        let active: HashSet<String> = self
+            .lines
+            .iter()
+            .flat_map(|l| l.hosts.iter().flatten())
+            .map(|h| h.to_ascii_lowercase())
+            .collect();
+        self.lines
+            .iter()
+            .enumerate()
+            .filter(|(_, l)| l.is_comment())
+            .filter(|(_, l)| {
+                let body = l.comment_body().unwrap_or_default();
+                let mentioned: Vec<String> = body
+                    .split_whitespace()
+                    .map(|t| t.trim_matches(|c: char| !c.is_ascii_alphanumeric()))
+                    .filter(|t| t.contains('.') && parse_ip(t).is_none())
+                    .filter(|t| is_valid_hostname(t, true))
+                    .map(|t| t.to_ascii_lowercase())
+                    .collect();
+                !mentioned.is_empty() && !mentioned.iter().any(|m| active.contains(m))
+            })
+            .map(|(i, _)| i)
+            .collect()
+    }
+    // Lines kept verbatim by a loose parse, as (line index, raw text).
+    pub fn unknown_lines(&self) -> Vec<(usize, &str)> {
+        self.lines
+            .iter()
+            .enumerate()
+            .filter_map(|(i, l)| l.unknown.as_deref().map(|raw| (i, raw)))
+            .collect()
+    }
+    pub fn comment_lines_mut(&mut self) -> impl Iterator<Item = &mut HostsFileLine> {
+        self.lines.iter_mut().filter(|l| l.is_comment())
+    }
+    // Splits a bundle at comment lines starting with `marker_prefix`, e.g. `# ---FILE: name---`
+    // with prefix `# ---FILE:`. Each part is named by the rest of its marker, trimmed of
+    // whitespace and trailing dashes; lines before the first marker form a part named "".
+    pub fn split_by_marker(&self, marker_prefix: &str) -> Vec<(String, HostsFile)> {
+        let mut parts: Vec<(String, HostsFile)> = vec![];
+        let mut name = String::new();
+        let mut lines: Vec<HostsFileLine> = vec![];
+        for line in &self.lines {
+            let marker = line
+                .comment
+                .as_deref()
+                .filter(|_| line.is_comment())
+                .and_then(|c| c.trim().strip_prefix(marker_prefix));
+            match marker {
+                Some(rest) => {
+                    if !name.is_empty() || !lines.is_empty() {
+                        parts.push((name, HostsFile { lines }));
+                    }
+                    name = rest.trim().trim_end_matches('-').trim_end().to_string();
+                    lines = vec![];
+                }
+                None => lines.push(line.clone()),
+            }
+        }
+        if !name.is_empty() || !lines.is_empty() {
+            parts.push((name, HostsFile { lines }));
+        }
+        parts
+    }
+    // The indices of the lines between a `begin` and a following `end` marker comment, both
+    // markers excluded.
+    pub fn managed_region(&self, begin: &str, end: &str) -> Option<Range<usize>> {
+        let start = self.lines.iter().position(|l| is_marker(l, begin))? + 1;
+        let len = self.lines[start..].iter().position(|l| is_marker(l, end))?;
+        Some(start..start + len)
+    }
+    // Replaces the contents of the managed region with `lines`. If only the `begin` marker
+    // exists, `lines` and the `end` marker are inserted right after it; with no marker at all
+    // the whole block is appended at the end of the file.
+    pub fn replace_managed_region(&mut self, begin: &str, end: &str, lines: Vec<HostsFileLine>) {
+        if let Some(range) = self.managed_region(begin, end) {
+            self.lines.splice(range, lines);
+            return;
+        }
+        let at = match self.lines.iter().position(|l| is_marker(l, begin)) {
+            Some(i) => i + 1,
+            None => {
+                self.lines.push(HostsFileLine::from_comment(begin));
+                self.lines.len()
+            }
+        };
+        let end = HostsFileLine::from_comment(end);
+        self.lines
+            .splice(at..at, lines.into_iter().chain(std::iter::once(end)));
+    }
+    // Replaces the region between `begin` and `end` in the file at `path` (appending it if
+    // absent) and writes the result back with `write_atomic`. A symlinked path is resolved
+    // first so the link stays in place. All other lines are kept byte for byte.
+    pub fn update_managed_block<P: AsRef<Path>>(
+        path: P,
+        begin: &str,
+        end: &str,
+        mut new_lines: Vec<HostsFileLine>,
+    ) -> Result<(), HostsError> {
+        // Resolve a symlinked hosts file so the link itself is not replaced.
+        let path = std::fs::canonicalize(path)?;
+        let mut file = HostsFile::from_string(&std::fs::read_to_string(&path)?)?;
+        let ending = file.lines.iter().find_map(|l| l.line_ending);
+        // Inserted lines take the file's ending, even ones parsed with `from_string`.
+        for line in new_lines.iter_mut() {
+            if line.line_ending.is_none() {
+                line.line_ending = ending;
+            }
+        }
+        file.replace_managed_region(begin, end, new_lines);
+        for line in file.lines.iter_mut() {
+            if line.original.is_none() && line.line_ending.is_none() {
+                line.line_ending = ending;
+            }
+        }
+        write_atomic(&path, &file.serialize_preserving())?;
+        Ok(())
+    }
+    // Runs of consecutive comment lines as (index of first line, comment bodies).
+    pub fn comment_blocks(&self) -> Vec<(usize, Vec<String>)> {
+        let mut blocks: Vec<(usize, Vec<String>)> = vec![];
+        let mut previous_was_comment = false;
+        for (i, line) in self.lines.iter().enumerate() {
+            match line.comment_body() {
+                Some(body) if line.is_comment() => {
+                    if previous_was_comment {
+                        blocks.last_mut().unwrap().1.push(body);
+                    } else {
+                        blocks.push((i, vec![body]));
+                    }
+                    previous_was_comment = true;
+                }
+                _ => previous_was_comment = false,
+            }
+        }
+        blocks
+    }
+    // Repoints every line whose address equals `old` to `new`, returning the lines changed.
+    pub fn rename_ip(&mut self, old: &str, new: &str) -> Result<usize, ParseError> {
+        let new = canonical_ip(new).ok_or_else(|| ParseError::new(ParseErrorKind::InvalidIp))?;
+        let old = parse_ip(old).ok_or_else(|| ParseError::new(ParseErrorKind::InvalidIp))?;
+        let mut changed = 0;
+        for line in self.lines.iter_mut() {
+            if line.ip_addr() == Some(old) {
+                line.ip = Some(new.clone());
+                line.mark_modified();
+                changed += 1;
+            }
+        }
+        Ok(changed)
+    }
+    // Renames hostname `old` to `new` (case-insensitive match) only on lines pointing at `ip`;
+    // `old` is just dropped where `new` is already listed. Returns the number of lines changed.
+    pub fn rename_hostname_on_ip(
+        &mut self,
+        ip: &str,
+        old: &str,
+        new: &str,
+    ) -> Result<usize, ParseError> {
+        let ip = parse_ip(ip).ok_or_else(|| ParseError::new(ParseErrorKind::InvalidIp))?;
+        if !is_host_token(new) {
+            return Err(ParseError::new(ParseErrorKind::InvalidHostname));
+        }
+        let mut changed = 0;
+        for line in self.lines.iter_mut() {
+            if line.ip_addr() != Some(ip) || !line.matches_hostname(old) {
+                continue;
+            }
+            if let Some(hosts) = line.hosts.as_mut() {
+                let mut seen_new = false;
+                hosts.retain_mut(|h| {
+                    if h.eq_ignore_ascii_case(old) {
+                        *h = new.to_string();
+                    }
+                    if !h.eq_ignore_ascii_case(new) {
+                        return true;
+                    }
+                    !std::mem::replace(&mut seen_new, true)
+                });
+            }
+            line.mark_modified();
+            changed += 1;
+        }
+        Ok(changed)
+    }
+    // Host lines whose address numerically equals `ip`, as (line index, hostnames).
+    pub fn entries_pointing_to(&self, ip: &str) -> Vec<(usize, Vec<String>)> {
+        let ip = match parse_ip(ip) {
+            Some(ip) => ip,
+            None => return vec![],
+        };
+        self.lines
+            .iter()
+            .enumerate()
+            .filter(|(_, l)| l.ip_addr() == Some(ip))
+            .map(|(i, l)| (i, l.hosts()))
+            .collect()
+    }
+    // Hostnames on lines whose address is written exactly as `ip_str`, zone included; unlike
+    // `entries_pointing_to` no numeric comparison is made.
+    pub fn hosts_for_ip_string(&self, ip_str: &str) -> Vec<String> {
+        self.lines
+            .iter()
+            .filter(|l| l.ip.as_deref() == Some(ip_str))
+            .flat_map(|l| l.hosts.iter().flatten().cloned())
+            .collect()
+    }
+    pub fn validate(&self) -> Vec<ValidationIssue> {
+        self.validate_with(&ValidationOptions::default())
+    }
+    pub fn validate_with(&self, options: &ValidationOptions) -> Vec<ValidationIssue> {
+        let mut issues = vec![];
+        for (i, line) in self.lines.iter().enumerate() {
+            if line.comment.as_ref().map_or(false, |c| c.contains('\t')) {
+                issues.push(ValidationIssue::TabInComment { line: i });
+            }
+            let comment_len = line.comment.as_ref().map_or(0, |c| c.chars().count());
+            if comment_len > options.max_comment_len {
+                issues.push(ValidationIssue::CommentTooLong {
+                    line: i,
+                    len: comment_len,
+                });
+            }
+            if line.has_host()
+                && line.original.as_ref().map_or(false, |o| {
+                    // Only the address and hostnames count; tabs inside the comment are fine.
+                    let fields = 1 + line.hosts.as_ref().map_or(0, Vec::len);
+                    let end = o
+                        .split_whitespace()
+                        .nth(fields)
+                        .map_or(o.len(), |t| t.as_ptr() as usize - o.as_ptr() as usize);
+                    o.starts_with(char::is_whitespace) || o[..end].contains('\t')
+                })
+            {
+                issues.push(ValidationIssue::Indentation { line: i });
+            }
+            if line.ip_addr().map_or(false, |ip| ip.is_unspecified())
+                && line.comment.is_none()
+                && line.leading_comments.is_empty()
+            {
+                issues.push(ValidationIssue::NullRouteWithComment { line: i });
+            }
+            if line.has_host()
+                && line
+                    .original
+                    .as_ref()
+                    .map_or(false, |o| o.ends_with(char::is_whitespace))
+            {
+                issues.push(ValidationIssue::TrailingWhitespace { line: i });
+            }
+            if line.ip_addr().map_or(false, |ip| ip.is_loopback()) {
+                for host in line.hosts.iter().flatten().filter(|h| looks_public(h)) {
+                    issues.push(ValidationIssue::LoopbackShadow {
+                        hostname: host.clone(),
+                        line: i,
+                    });
+                }
+            }
+        }
+        issues
+    }
+    // Parses `s` and appends its lines; nothing is appended if any line fails to parse.
+    pub fn extend_from_str(&mut self, s: &str) -> Result<(), ParseError> {
+        let parsed = HostsFile::from_string(s)?;
+        self.lines.extend(parsed.lines);
+        Ok(())
+    }
+    pub fn add_entry(
+        &mut self,
+        ip: &str,
+        hosts: &[&str],
+        comment: Option<&str>,
+    ) -> Result<(), ParseError> {
+        let mut line = HostsFileLine::from_host(ip, hosts)?;
+        line.comment = comment.map(normalize_comment);
+        self.lines.push(line);
+        Ok(())
+    }
+    pub fn append_comment(&mut self, text: &str) {
+        self.lines
+            .push(HostsFileLine::from_comment(&normalize_comment(text)));
+    }
+    pub fn append_blank(&mut self) {
+        self.lines.push(HostsFileLine::from_empty());
+    }
+    // Like `add_entry`, for callers already holding an address; it is written in canonical form.
+    pub fn add_entry_addr(
+        &mut self,
+        ip: IpAddr,
+        hosts: &[&str],
+        comment: Option<&str>,
+    ) -> Result<(), ParseError> {
+        let mut line = HostsFileLine::from_checked_ip(ip.to_string(), hosts)?;
+        line.comment = comment.map(normalize_comment);
+        self.lines.push(line);
+        Ok(())
+    }
+    // Inserts `line` before position `index` (`index == lines.len()` appends). Returns false
+    // and leaves the file untouched when `index` is out of range.
+    pub fn insert_line(&mut self, index: usize, line: HostsFileLine) -> bool {
+        if index > self.lines.len() {
+            return false;
+        }
+        self.lines.insert(index, line);
+        true
+    }
+    // Adds `127.0.0.1 localhost` and `::1 localhost` at the top of the file when missing.
+    // Returns the number of lines added.
+    pub fn ensure_localhost(&mut self) -> usize {
+        let mut added = 0;
+        for ip in ["127.0.0.1", "::1"] {
+            if !self.contains_exact_mapping(ip, "localhost") {
+                let line = HostsFileLine::from_host(ip, &["localhost"]).unwrap();
+                self.insert_line(added, line);
+                added += 1;
+            }
+        }
+        added
+    }
+    // Overwrites the line at `index`, returning the previous one, or `None` if out of range.
+    pub fn replace_line_at(&mut self, index: usize, line: HostsFileLine) -> Option<HostsFileLine> {
+        self.lines
+            .get_mut(index)
+            .map(|slot| std::mem::replace(slot, line))
+    }
+    // Swaps the lines at `i` and `j`. Returns false and leaves the file untouched when
+    // either index is out of range.
+    pub fn swap_lines(&mut self, i: usize, j: usize) -> bool {
+        if i >= self.lines.len() || j >= self.lines.len() {
+            return false;
+        }
+        self.lines.swap(i, j);
+        true
+    }
+    // Inserts `line` right after the first host line containing `anchor_hostname`.
+    pub fn insert_after_host(&mut self, anchor_hostname: &str, line: HostsFileLine) -> bool {
+        match self
+            .lines
+            .iter()
+            .position(|l| l.matches_hostname(anchor_hostname))
+        {
+            Some(i) => self.insert_line(i + 1, line),
+            None => false,
+        }
+    }
+    pub fn clear(&mut self) {
+        self.lines.clear();
+    }
+    // Removes every host line, keeping comments, blank lines and unknown lines. Comments
+    // attached to an entry stay behind as standalone comment lines.
+    pub fn clear_entries(&mut self) {
+        let mut lines: Vec<HostsFileLine> = Vec::with_capacity(self.lines.len());
+        for mut line in self.lines.drain(..) {
+            lines.append(&mut line.leading_comments);
+            if !line.has_host() {
+                lines.push(line);
+            }
+        }
+        self.lines = lines;
+    }
+    // Keeps the first `max` host lines in document order and removes the later ones;
+    // comments, blank lines and unknown lines stay.
+    pub fn truncate_entries(&mut self, max: usize) {
+        let mut kept = 0;
+        self.lines.retain(|l| {
+            if !l.has_host() {
+                return true;
+            }
+            kept += 1;
+            kept <= max
+        });
+    }
+    // Removes `hostname` from every line, dropping lines (and their comments) left without
+    // hosts. Returns the number of lines changed.
+    pub fn remove_host(&mut self, hostname: &str) -> usize {
+        let mut changed = 0;
+        for line in self
+            .lines
+            .iter_mut()
+            .filter(|l| l.matches_hostname(hostname))
+        {
+            if let Some(hosts) = line.hosts.as_mut() {
+                hosts.retain(|h| !h.eq_ignore_ascii_case(hostname));
+            }
+            line.mark_modified();
+            changed += 1;
+        }
+        self.lines
+            .retain(|l| !l.has_host() || l.hosts.as_ref().map_or(false, |h| !h.is_empty()));
+        changed
+    }
+    // Points `hostname` at `ip`. Lines where it is the only host get the new address; where it
+    // shares a line with other names it is split out onto its own line right below.
+    pub fn set_ip_for(&mut self, hostname: &str, ip: &str) -> Result<usize, ParseError> {
+        self.repoint_host(hostname, ip, None)
+    }
+    // Makes `hostname` resolve to `ip` through exactly one mapping. The first line already
+    // pointing there is kept; otherwise the first line listing the name is repointed (split
+    // out of a shared line) or, if there is none, an entry is appended. The name is then
+    // removed from every other line, dropping lines left without hostnames.
+    pub fn ensure_mapping(&mut self, hostname: &str, ip: IpAddr) -> Result<(), ParseError> {
+        let keep = match self
+            .lines
+            .iter()
+            .position(|l| l.matches_hostname(hostname) && l.ip_addr() == Some(ip))
+        {
+            Some(i) => i,
+            None => match self.lines.iter().position(|l| l.matches_hostname(hostname)) {
+                None => return self.add_entry_addr(ip, &[hostname], None),
+                Some(i) if self.lines[i].hosts().len() == 1 => {
+                    self.lines[i].set_ip(&ip.to_string())?;
+                    i
+                }
+                Some(i) => {
+                    let line = HostsFileLine::from_checked_ip(ip.to_string(), &[hostname])?;
+                    self.lines.insert(i + 1, line);
+                    i + 1
+                }
+            },
+        };
+        for (i, line) in self.lines.iter_mut().enumerate() {
+            if i == keep || !line.matches_hostname(hostname) {
+                continue;
+            }
+            if let Some(hosts) = line.hosts.as_mut() {
+                hosts.retain(|h| !h.eq_ignore_ascii_case(hostname));
+            }
+            line.mark_modified();
+        }
+        self.lines
+            .retain(|l| !l.has_host() || l.hosts.as_ref().map_or(false, |h| !h.is_empty()));
+        Ok(())
+    }
+    // `ensure_mapping` for each override; new entries are appended in hostname order.
+    pub fn apply_overrides(
+        &mut self,
+        overrides: &HashMap<String, IpAddr>,
+    ) -> Result<(), ParseError> {
+        let mut hostnames: Vec<&String> = overrides.keys().collect();
+        hostnames.sort();
+        for hostname in hostnames {
+            self.ensure_mapping(hostname, overrides[hostname])?;
+        }
+        Ok(())
+    }
+    // `set_ip_for` restricted to lines currently using the address `only_on`, if given.
+    fn repoint_host(
+        &mut self,
+        hostname: &str,
+        ip: &str,
+        only_on: Option<IpAddr>,
+    ) -> Result<usize, ParseError> {
+        let new_line = HostsFileLine::from_host(ip, &[hostname])?;
+        let mut changed = 0;
+        let mut i = 0;
+        while i < self.lines.len() {
+            let line = &self.lines[i];
+            if line.matches_hostname(hostname)
+                && only_on.map_or(true, |only| line.ip_addr() == Some(only))
+            {
+                changed += 1;
+                let others = line
+                    .hosts()
+                    .iter()
+                    .filter(|h| !h.eq_ignore_ascii_case(hostname))
+                    .count();
+                if others == 0 {
+                    self.lines[i].set_ip(ip)?;
+                } else {
+                    let line = &mut self.lines[i];
+                    if let Some(hosts) = line.hosts.as_mut() {
+                        hosts.retain(|h| !h.eq_ignore_ascii_case(hostname));
+                    }
+                    line.mark_modified();
+                    self.lines.insert(i + 1, new_line.clone());
+                    i += 1;
+                }
+            }
+            i += 1;
+        }
+        Ok(changed)
+    }
+    // Turns every host line containing `hostname` into a comment, other names on those lines
+    // included. Returns the number of lines commented out.
+    pub fn comment_out_host(&mut self, hostname: &str) -> usize {
+        let mut changed = 0;
+        for line in self
+            .lines
+            .iter_mut()
+            .filter(|l| l.matches_hostname(hostname))
+        {
+            let leading_comments = std::mem::take(&mut line.leading_comments);
+            *line = HostsFileLine::from_comment(&format!("# {}", line));
+            line.leading_comments = leading_comments;
+            changed += 1;
+        }
+        changed
+    }
+    // Applies `ops` in order, returning the number of lines each one changed. If any op fails
+    // the file is restored to its state before the call.
+    pub fn apply_patch(&mut self, ops: &[HostOp]) -> Result<Vec<usize>, ParseError> {
+        let backup = self.clone();
+        let mut summary = Vec::with_capacity(ops.len());
+        for op in ops {
+            let result = match op {
+                HostOp::Add { ip, hosts } => {
+                    let hosts: Vec<&str> = hosts.iter().map(|h| h.as_str()).collect();
+                    self.add_entry(ip, &hosts, None).map(|_| 1)
+                }
+                HostOp::Remove { hostname } => Ok(self.remove_host(hostname)),
+                HostOp::SetIp { hostname, ip } => self.set_ip_for(hostname, ip),
+                HostOp::Comment { hostname } => Ok(self.comment_out_host(hostname)),
+            };
+            match result {
+                Ok(n) => summary.push(n),
+                Err(e) => {
+                    *self = backup;
+                    return Err(e);
+                }
+            }
+        }
+        Ok(summary)
+    }
+    pub fn first_entry(&self) -> Option<&HostsFileLine> {
+        self.lines.iter().find(|l| l.has_host())
+    }
+    pub fn last_entry(&self) -> Option<&HostsFileLine> {
+        self.lines.iter().rev().find(|l| l.has_host())
+    }
+    // The line at `index` in `lines`, of any kind, or `None` if out of range.
+    pub fn entry_at(&self, index: usize) -> Option<&HostsFileLine> {
+        self.lines.get(index)
+    }
+    pub fn entry_at_mut(&mut self, index: usize) -> Option<&mut HostsFileLine> {
+        self.lines.get_mut(index)
+    }
+    pub fn find_line<F: Fn(&HostsFileLine) -> bool>(&self, f: F) -> Option<&HostsFileLine> {
+        self.lines.iter().find(|l| f(l))
+    }
+    pub fn lines_matching<F: Fn(&HostsFileLine) -> bool>(&self, f: F) -> Vec<&HostsFileLine> {
+        self.lines.iter().filter(|l| f(l)).collect()
+    }
+    // The first host line containing `hostname` (case-insensitive).
+    pub fn find_line_mut(&mut self, hostname: &str) -> Option<&mut HostsFileLine> {
+        self.lines.iter_mut().find(|l| l.matches_hostname(hostname))
+    }
+    // The address of the first line mapping `hostname` (case-insensitive).
+    pub fn lookup(&self, hostname: &str) -> Option<IpAddr> {
+        self.lookup_cs(hostname, false)
+    }
+    // `lookup` with exact-case matching when `case_sensitive` is set.
+    pub fn lookup_cs(&self, hostname: &str, case_sensitive: bool) -> Option<IpAddr> {
+        self.iter_hosts()
+            .find(|(_, h)| hostname_eq(h, hostname, case_sensitive))
+            .map(|(ip, _)| ip)
+    }
+    pub fn contains_host(&self, hostname: &str) -> bool {
+        self.contains_host_cs(hostname, false)
+    }
+    pub fn contains_ip(&self, ip: &str) -> bool {
+        match parse_ip(ip) {
+            Some(ip) => self.lines.iter().any(|l| l.ip_addr() == Some(ip)),
+            None => false,
+        }
+    }
+    // Whether some host line maps `hostname` (case-insensitive) to the same address as `ip`.
+    pub fn contains_exact_mapping(&self, ip: &str, hostname: &str) -> bool {
+        match parse_ip(ip) {
+            Some(ip) => self
+                .lines
+                .iter()
+                .any(|l| l.ip_addr() == Some(ip) && l.matches_hostname(hostname)),
+            None => false,
+        }
+    }
+    pub fn contains_host_cs(&self, hostname: &str, case_sensitive: bool) -> bool {
+        self.lines
+            .iter()
+            .flat_map(|l| l.hosts.iter().flatten())
+            .any(|h| hostname_eq(h, hostname, case_sensitive))
+    }
+    pub fn first_ip_for(&self, hostname: &str) -> Option<IpAddr> {
+        self.lookup(hostname)
+    }
+    // Every distinct address `hostname` maps to, in document order.
+    pub fn all_ips_for(&self, hostname: &str) -> Vec<IpAddr> {
+        let mut ips: Vec<IpAddr> = vec![];
+        for (ip, _) in self
+            .iter_hosts()
+            .filter(|(_, h)| h.eq_ignore_ascii_case(hostname))
+        {
+            if !ips.contains(&ip) {
+                ips.push(ip);
+            }
+        }
+        ips
+    }
+    pub fn stats(&self) -> HostsStats {
+        let mut stats = HostsStats {
+            total_lines: self.lines.len(),
+            ..HostsStats::default()
+        };
+        let mut lines_per_host: HashMap<String, usize> = HashMap::new();
+        let mut ips: HashSet<String> = HashSet::new();
+        for line in &self.lines {
+            if line.is_empty() {
+                stats.empty_lines += 1;
+            } else if line.is_comment() {
+                stats.comment_lines += 1;
+            } else if let Some(ip) = &line.ip {
+                stats.host_entries += 1;
+                ips.insert(line.ip_addr().map_or(ip.clone(), |ip| ip.to_string()));
+                let mut seen: HashSet<String> = HashSet::new();
+                for host in line.hosts.iter().flatten() {
+                    let host = host.to_ascii_lowercase();
+                    if seen.insert(host.clone()) {
+                        *lines_per_host.entry(host).or_insert(0) += 1;
+                    }
+                }
+            }
+        }
+        stats.unique_hostnames = lines_per_host.len();
+        stats.unique_ips = ips.len();
+        stats.duplicate_hostnames = lines_per_host.values().filter(|n| **n > 1).count();
+        stats
+    }
+    // The inline comment of the first host line containing `hostname`.
+    pub fn comment_for(&self, hostname: &str) -> Option<String> {
+        self.lines
+            .iter()
+            .find(|l| l.matches_hostname(hostname))
+            .and_then(|l| l.comment())
+    }
+    // Number of (address, hostname) mappings across all host lines.
+    pub fn count_hosts(&self) -> usize {
+        self.lines
+            .iter()
+            .filter_map(|l| l.hosts.as_ref())
+            .map(|h| h.len())
+            .sum()
+    }
+    // Hostnames beginning with `prefix` (case-insensitive), each once, in document order.
+    pub fn hostnames_starting_with(&self, prefix: &str) -> Vec<String> {
+        let mut seen: HashSet<String> = HashSet::new();
+        self.lines
+            .iter()
+            .flat_map(|l| l.hosts.iter().flatten())
+            .filter(|h| {
+                h.get(..prefix.len())
+                    .map_or(false, |p| p.eq_ignore_ascii_case(prefix))
+            })
+            .filter(|h| seen.insert(h.to_ascii_lowercase()))
+            .cloned()
+            .collect()
+    }
+    // Number of (IPv4, IPv6) host lines; lines with an invalid address are not counted.
+    pub fn ip_families(&self) -> (usize, usize) {
+        self.lines
+            .iter()
+            .filter_map(|l| l.ip_addr())
+            .fold((0, 0), |(v4, v6), ip| match ip {
+                IpAddr::V4(_) => (v4 + 1, v6),
+                IpAddr::V6(_) => (v4, v6 + 1),
+            })
+    }
+    // One (address, hostname) pair per hostname; lines with an invalid address are skipped.
+    pub fn iter_hosts(&self) -> impl Iterator<Item = (IpAddr, &str)> + '_ {
+        self.lines
+            .iter()
+            .filter_map(|line| line.ip_addr().map(|ip| (ip, line)))
+            .flat_map(|(ip, line)| line.hosts.iter().flatten().map(move |h| (ip, h.as_str())))
+    }
+    // Only ASCII letters are lowercased so IDN (punycode or UTF-8) names are left intact.
+    pub fn lowercase_hostnames(&mut self) {
+        for line in self.lines.iter_mut() {
+            let hosts = match line.hosts.as_mut() {
+                Some(hosts) => hosts,
+                None => continue,
+            };
+            if hosts
+                .iter()
+                .any(|h| h.bytes().any(|b| b.is_ascii_uppercase()))
+            {
+                hosts.iter_mut().for_each(|h| h.make_ascii_lowercase());
+                line.mark_modified();
+            }
+        }
+    }
+    // Replaces every hostname with `f(hostname)`, dropping names the mapping makes duplicate.
+    // If `f` returns an empty name, one with whitespace or one starting with `#`, nothing is
+    // changed and `ParseErrorKind::InvalidHostname` is returned.
+    pub fn map_hostnames<F: FnMut(&str) -> String>(&mut self, mut f: F) -> Result<(), ParseError> {
+        let mut names: Vec<String> = vec![];
+        for host in self.lines.iter().flat_map(|l| l.hosts.iter().flatten()) {
+            let new = f(host);
+            if !is_host_token(&new) {
+                return Err(ParseError::new(ParseErrorKind::InvalidHostname));
+            }
+            names.push(new);
+        }
+        let mut names = names.into_iter();
+        self.remap_hostnames(|_| names.next().unwrap_or_default());
+        Ok(())
+    }
+    // `map_hostnames` without checking the new names; `f` sees hosts in file order.
+    fn remap_hostnames<F: FnMut(&str) -> String>(&mut self, mut f: F) {
+        for line in self.lines.iter_mut() {
+            let hosts = match line.hosts.as_mut() {
+                Some(hosts) => hosts,
+                None => continue,
+            };
+            let mut mapped: Vec<String> = Vec::with_capacity(hosts.len());
+            for host in hosts.iter() {
+                let new = f(host);
+                if !mapped.iter().any(|m| m.eq_ignore_ascii_case(&new)) {
+                    mapped.push(new);
+                }
+            }
+            if *hosts != mapped {
+                *hosts = mapped;
+                line.mark_modified();
+            }
+        }
+    }
+    // Moves each run of comment lines directly above a host line into that line's
+    // `leading_comments`, so reordering or removing the entry carries its documentation along.
+    pub fn attach_leading_comments(&mut self) {
+        let mut lines: Vec<HostsFileLine> = Vec::with_capacity(self.lines.len());
+        let mut pending: Vec<HostsFileLine> = vec![];
+        for mut line in self.lines.drain(..) {
+            if line.is_comment() {
+                pending.push(line);
+                continue;
+            }
+            if line.has_host() {
+                line.leading_comments.append(&mut pending);
+            }
+            lines.append(&mut pending);
+            lines.push(line);
+        }
+        lines.append(&mut pending);
+        self.lines = lines;
+    }
+    // Turns attached leading comments back into standalone comment lines.
+    pub fn detach_leading_comments(&mut self) {
+        let mut lines: Vec<HostsFileLine> = Vec::with_capacity(self.lines.len());
+        for mut line in self.lines.drain(..) {
+            lines.append(&mut line.leading_comments);
+            lines.push(line);
+        }
+        self.lines = lines;
+    }
+    // Stable sort of the host lines among themselves; comment, blank and unknown lines keep
+    // their positions.
+    pub fn sort_entries_by<F>(&mut self, mut compare: F)
+    where
+        F: FnMut(&HostsFileLine, &HostsFileLine) -> Ordering,
+    {
+        let slots: Vec<usize> = (0..self.lines.len())
+            .filter(|i| self.lines[*i].has_host())
+            .collect();
+        let mut entries: Vec<HostsFileLine> = slots
+            .iter()
+            .map(|i| std::mem::replace(&mut self.lines[*i], HostsFileLine::from_empty()))
+            .collect();
+        entries.sort_by(|a, b| compare(a, b));
+        for (slot, entry) in slots.into_iter().zip(entries) {
+            self.lines[slot] = entry;
+        }
+    }
+    // Orders host lines by the labels of their first hostname read right to left, so that
+    // `a.example.com` and `b.example.com` end up next to each other. Comment, blank and
+    // unknown lines separate sections that are sorted independently.
+    pub fn sort_by_domain(&mut self) {
+        fn domain_key(line: &HostsFileLine) -> Vec<String> {
+            line.hosts
+                .as_ref()
+                .and_then(|hosts| hosts.first())
+                .map(|host| {
+                    host.trim_end_matches('.')
+                        .rsplit('.')
+                        .map(|label| label.to_ascii_lowercase())
+                        .collect()
+                })
+                .unwrap_or_default()
+        }
+        for section in self.lines.split_mut(|l| !l.has_host()) {
+            section.sort_by_cached_key(domain_key);
+        }
+    }
+    // Sorts the hostnames of each line case-insensitively; with `pin_primary` the first
+    // hostname stays in place and only the aliases after it are sorted.
+    pub fn sort_hosts_within_lines(&mut self, pin_primary: bool) {
+        let skip = if pin_primary { 1 } else { 0 };
+        for line in self.lines.iter_mut() {
+            let hosts = match line.hosts.as_mut() {
+                Some(hosts) if hosts.len() > skip => hosts,
+                _ => continue,
+            };
+            let before = hosts.clone();
+            hosts[skip..].sort_by_key(|h| h.to_ascii_lowercase());
+            if *hosts != before {
+                line.mark_modified();
+            }
+        }
+    }
+    // Converts Unicode hostnames to their ASCII (punycode) form; names that are already ASCII
+    // or fail IDNA processing are left as they are.
+    #[cfg(feature = "idna")]
+    pub fn normalize_idn(&mut self) {
+        self.remap_hostnames(|h| match idna::domain_to_ascii(h) {
+            Ok(ascii) if !h.is_ascii() && is_host_token(&ascii) => ascii,
+            _ => h.to_string(),
+        });
+    }
+    // `lookup` comparing names in their IDNA ASCII form, so `bücher.example` and
+    // `xn--bcher-kva.example` match each other.
+    #[cfg(feature = "idna")]
+    pub fn lookup_idn(&self, hostname: &str) -> Option<IpAddr> {
+        let key = |h: &str| idna::domain_to_ascii(h).unwrap_or_else(|_| h.to_ascii_lowercase());
+        let wanted = key(hostname);
+        self.iter_hosts()
+            .find(|(_, h)| key(h) == wanted)
+            .map(|(ip, _)| ip)
+    }
+    // Re-renders host lines with single spaces between fields; comments and blank lines
+    // keep their original text.
+    pub fn normalize_whitespace(&mut self) {
+        for line in self.lines.iter_mut().filter(|l| l.has_host()) {
+            line.mark_modified();
+        }
+    }
+    // Removes comment lines identical to the comment line right before them.
+    pub fn collapse_duplicate_comments(&mut self) {
+        self.lines.dedup_by(|line, previous| {
+            line.is_comment()
+                && previous.is_comment()
+                && line.leading_comments.is_empty()
+                && line.comment == previous.comment
+        });
+    }
+    // Whether the parsed lines consistently end in LF or CRLF. Lines that were added or
+    // that ended the input without a newline are not counted.
+    pub fn line_ending_consistency(&self) -> LineEndingReport {
+        let (lf, crlf) = self
+            .lines
+            .iter()
+            .fold((0, 0), |(lf, crlf), l| match l.line_ending {
+                Some(LineEnding::Lf) => (lf + 1, crlf),
+                Some(LineEnding::CrLf) => (lf, crlf + 1),
+                None => (lf, crlf),
+            });
+        match (lf, crlf) {
+            (0, 0) => LineEndingReport::Unknown,
+            (lf, 0) => LineEndingReport::Lf(lf),
+            (0, crlf) => LineEndingReport::CrLf(crlf),
+            (lf, crlf) => LineEndingReport::Mixed { lf, crlf },
+        }
+    }
+    // Drops the preserved text of lines that originally ended in whitespace so they
+    // re-render without it.
+    pub fn trim_lines(&mut self) {
+        for line in self.lines.iter_mut() {
+            if line
+                .original
+                .as_ref()
+                .map_or(false, |o| o.ends_with(char::is_whitespace))
+            {
+                line.mark_modified();
+            }
+        }
+    }
+    // Normalizes the spacing of every comment, standalone, inline or attached; see
+    // `normalize_comment_spacing`.
+    pub fn normalize_comments(&mut self) {
+        fn normalize(line: &mut HostsFileLine) {
+            let normalized = line.comment.as_deref().map(normalize_comment_spacing);
+            if normalized != line.comment {
+                line.comment = normalized;
+                line.mark_modified();
+            }
+        }
+        for line in self.lines.iter_mut() {
+            line.leading_comments.iter_mut().for_each(normalize);
+            normalize(line);
+        }
+    }
+    pub fn trim_trailing_whitespace(&mut self) {
+        for line in self.lines.iter_mut() {
+            if let Some(comment) = line.comment.as_mut() {
+                let trimmed = comment.trim_end().len();
+                if trimmed < comment.len() {
+                    comment.truncate(trimmed);
+                    line.mark_modified();
+                }
+            }
+        }
+    }
+    // Repoints lines using `from_ip` to `to_ip`. With a `hostname`, only that name is moved
+    // (split out of shared lines as in `set_ip_for`); without one this is `rename_ip`.
+    // Nothing is touched when both addresses are the same.
+    pub fn replace_all(
+        &mut self,
+        from_ip: &str,
+        to_ip: &str,
+        hostname: Option<&str>,
+    ) -> Result<usize, ParseError> {
+        let from = parse_ip(from_ip).ok_or_else(|| ParseError::new(ParseErrorKind::InvalidIp))?;
+        let to = canonical_ip(to_ip).ok_or_else(|| ParseError::new(ParseErrorKind::InvalidIp))?;
+        if canonical_ip(from_ip).as_deref() == Some(to.as_str()) {
+            return Ok(0);
+        }
+        match hostname {
+            Some(hostname) => self.repoint_host(hostname, &to, Some(from)),
+            None => self.rename_ip(from_ip, &to),
+        }
+    }
+    pub fn subnet_entries(&self, cidr: &str) -> Result<Vec<HostsFileHost>, ParseError> {
+        let (network, prefix) =
+            parse_cidr(cidr).ok_or_else(|| ParseError::new(ParseErrorKind::InvalidIp))?;
+        Ok(self
+            .lines
+            .iter()
+            .filter(|l| {
+                l.ip_addr()
+                    .map_or(false, |ip| cidr_contains(network, prefix, ip))
+            })
+            .filter_map(|l| l.host_entry())
+            .collect())
+    }
+    pub fn entries_with_comment(&self) -> Vec<HostsFileHost> {
+        self.lines
+            .iter()
+            .filter(|l| l.comment.is_some())
+            .filter_map(|l| l.host_entry())
+            .collect()
+    }
+    // Groups host entries by the value of `key:value` tokens in their inline comments, e.g.
+    // `# env:staging`. Entries without the tag are left out.
+    pub fn entries_by_tag(&self, key: &str) -> HashMap<String, Vec<HostsFileHost>> {
+        let mut groups: HashMap<String, Vec<HostsFileHost>> = HashMap::new();
+        for line in &self.lines {
+            let (entry, body) = match (line.host_entry(), line.comment_body()) {
+                (Some(entry), Some(body)) => (entry, body),
+                _ => continue,
+            };
+            let value = body
+                .split_whitespace()
+                .filter_map(|token| token.split_once(':'))
+                .find(|(k, _)| *k == key)
+                .map(|(_, v)| v.to_string());
+            if let Some(value) = value {
+                groups.entry(value).or_default().push(entry);
+            }
+        }
+        groups
+    }
+}
+
+// A read-only view over hosts file text. Entries borrow from the input, so scanning a file
+// does not allocate per hostname; use `HostsFile` when the content needs to be edited.
+#[derive(Debug, Eq, PartialEq)]
+pub struct HostsFileRef<'a> {
+    entries: Vec<HostsEntryRef<'a>>,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct HostsEntryRef<'a> {
+    ip: &'a str,
+    hosts: &'a str,
+    comment: Option<&'a str>,
+}
+
+impl<'a> HostsEntryRef<'a> {
+    pub fn ip(&self) -> &'a str {
+        self.ip
+    }
+    pub fn hosts(&self) -> impl Iterator<Item = &'a str> {
+        self.hosts.split_whitespace()
+    }
+    pub fn comment(&self) -> Option<&'a str> {
+        self.comment
+    }
+    fn parse(line: &'a str) -> Result<Option<HostsEntryRef<'a>>, ParseError> {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            return Ok(None);
+        }
+        let (ip, rest) = match line.find(char::is_whitespace) {
+            Some(i) => (&line[..i], line[i..].trim_start()),
+            None => return Err(ParseError::new(ParseErrorKind::InvalidLine)),
+        };
+        let comment_start = rest
+            .split_whitespace()
+            .find(|t| t.starts_with('#'))
+            .map(|t| t.as_ptr() as usize - rest.as_ptr() as usize);
+        let (hosts, comment) = match comment_start {
+            Some(i) => (rest[..i].trim_end(), Some(&rest[i..])),
+            None => (rest, None),
+        };
+        if hosts.is_empty() {
+            return Err(ParseError::new(ParseErrorKind::InvalidLine));
+        }
+        Ok(Some(HostsEntryRef { ip, hosts, comment }))
+    }
+}
+
+impl<'a> HostsFileRef<'a> {
+    pub fn parse(input: &'a str) -> Result<HostsFileRef<'a>, ParseError> {
+        let mut entries = vec![];
+        for (i, line) in input.lines().enumerate() {
+            if let Some(entry) = HostsEntryRef::parse(line).map_err(|e| e.at_line(i + 1))? {
+                entries.push(entry);
+            }
+        }
+        Ok(HostsFileRef { entries })
+    }
+    pub fn entries(&self) -> &[HostsEntryRef<'a>] {
+        &self.entries
+    }
+    // The address text of the first entry mapping `hostname` (case-insensitive).
+    pub fn lookup(&self, hostname: &str) -> Option<&'a str> {
+        self.entries
+            .iter()
+            .find(|e| e.hosts().any(|h| h.eq_ignore_ascii_case(hostname)))
+            .map(|e| e.ip)
+    }
+    pub fn contains(&self, hostname: &str) -> bool {
+        self.lookup(hostname).is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Parse tests
+    #[test]
+    fn from_empty() {
+        let parsed = HostsFileLine::from_empty();
+        let expected = HostsFileLine {
+            is_empty: true,
+            ip: None,
+            comment: None,
+            hosts: None,
+            unknown: None,
+            original: None,
+            line_ending: None,
+            leading_comments: vec![],
+        };
+        assert_eq!(parsed, expected);
+    }
+    #[test]
+    fn from_comment() {
+        let parsed = HostsFileLine::from_comment("#test");
+        let expected = HostsFileLine {
+            is_empty: false,
+            ip: None,
+            comment: Some("#test".to_string()),
+            hosts: None,
+            unknown: None,
+            original: None,
+            line_ending: None,
+            leading_comments: vec![],
+        };
+        assert_eq!(parsed, expected);
+    }
+
+    #[test]
+    fn indented_comment_from_string() {
+        // Parsing trims the comment; the indentation survives only in the original text.
+        let parsed = HostsFileLine::from_string("\t# tabbed comment").unwrap();
+        assert_eq!(parsed, HostsFileLine::from_comment("# tabbed comment"));
+        assert_eq!(parsed.to_string(), "# tabbed comment");
+        assert_eq!(parsed.original(), Some("\t# tabbed comment"));
+    }
+    #[test]
+    fn from_comment_raw() {
+        let line = HostsFileLine::from_comment_raw("\t# tabbed comment").unwrap();
+        assert!(line.is_comment());
+        assert_eq!(line.comment(), Some("\t# tabbed comment".to_string()));
+        assert_eq!(line.comment_body(), Some("tabbed comment".to_string()));
+        assert_eq!(line.to_string(), "\t# tabbed comment");
+        let line = HostsFileLine::from_comment_raw("    ## indented\n").unwrap();
+        assert_eq!(line.to_string(), "    ## indented");
+        for bad in ["127.0.0.1 foo", "  not a comment", "", "# a\n# b"] {
+            let err = HostsFileLine::from_comment_raw(bad).unwrap_err();
+            assert_eq!(err.kind(), &ParseErrorKind::InvalidLine);
+        }
+    }
+    #[test]
+    fn empty_line_from_string() {
+        let parsed = HostsFileLine::from_string("").unwrap();
+        let expected = HostsFileLine::from_empty();
+        assert_eq!(parsed, expected);
+    }
+    #[test]
+    fn comment_from_string() {
+        let parsed = HostsFileLine::from_string("# comment").unwrap();
+        let expected = HostsFileLine::from_comment("# comment");
+        assert_eq!(parsed, expected);
+    }
+
+    #[test]
+    fn broken_from_string() {
+        HostsFileLine::from_string("127.0.0.1").expect_err("should fail");
+    }
+    #[test]
+    fn host_from_string() {
+        let parsed = HostsFileLine::from_string("127.0.0.1 localhost").unwrap();
+        let expected = HostsFileLine {
+            is_empty: false,
+            ip: Some("127.0.0.1".to_string()),
+            hosts: Some(vec!["localhost".to_string()]),
+            comment: None,
+            unknown: None,
+            original: None,
+            line_ending: None,
+            leading_comments: vec![],
+        };
+        assert_eq!(parsed, expected);
+    }
+    #[test]
+    fn full_from_string() {
+        let parsed = HostsFileLine::from_string("127.0.0.1 localhost  # a comment").unwrap();
+        let expected = HostsFileLine {
+            is_empty: false,
+            ip: Some("127.0.0.1".to_string()),
+            hosts: Some(vec!["localhost".to_string()]),
+            comment: Some("# a comment".to_string()),
+            unknown: None,
+            original: None,
+            line_ending: None,
+            leading_comments: vec![],
+        };
+        assert_eq!(parsed, expected);
+    }
+    #[test]
+    fn many_aliases_from_string() {
+        let aliases: Vec<String> = (0..200).map(|i| format!("alias{}.example", i)).collect();
+        let input = format!("10.0.0.1 {} # generated by systemd", aliases.join(" "));
+        let parsed = HostsFileLine::from_string(&input).unwrap();
+        assert_eq!(parsed.hosts(), aliases);
+        assert_eq!(parsed.comment(), Some("# generated by systemd".to_string()));
+        assert_eq!(parsed.to_string(), input);
+    }
+    #[test]
+    fn empty_input() {
+        let parsed = HostsFile::from_str("").unwrap();
+        let expected = HostsFile { lines: vec![] };
+        assert_eq!(parsed, expected);
+    }
+    #[test]
+    fn a_comment() {
+        let parsed = HostsFile::from_str("# comment").unwrap();
+        let expected = HostsFile {
+            lines: vec![HostsFileLine::from_comment("# comment")],
+        };
+        assert_eq!(parsed, expected);
+    }
+    #[test]
+    fn two_comments() {
+        let parsed = HostsFile::from_str("# comment1\n## comment2\n").unwrap();
+        let expected = HostsFile {
+            lines: vec![
+                HostsFileLine::from_comment("# comment1"),
+                HostsFileLine::from_comment("## comment2"),
+            ],
+        };
+        assert_eq!(parsed, expected);
+    }
+    #[test]
+    fn host_with_comments() {
+        let parsed = HostsFile::from_str("127.0.0.1 localhost # comment\n").unwrap();
+        let expected = HostsFile {
+            lines: vec![HostsFileLine {
+                is_empty: false,
+                ip: Some("127.0.0.1".to_string()),
+                hosts: Some(vec!["localhost".to_string()]),
+                comment: Some("# comment".to_string()),
+                unknown: None,
+                original: None,
+                line_ending: None,
+                leading_comments: vec![],
+            }],
+        };
+        assert_eq!(parsed, expected);
+    }
+    #[test]
+    fn whitespace() {
+        let parsed = HostsFile::from_str(" # comment1\n \n    127.0.0.1    localhost\n").unwrap();
+        let expected = HostsFile {
+            lines: vec![
                 HostsFileLine::from_comment("# comment1"),
                 HostsFileLine::from_empty(),
                 HostsFileLine::from_string("127.0.0.1 localhost").unwrap(),
             ],
         };
-        assert_eq!(parsed, expected);
+        assert_eq!(parsed, expected);
+    }
+    #[test]
+    fn a_ipv6_host() {
+        let parsed = HostsFile::from_str("fe80::1%lo0 localhost\n").unwrap();
+        let expected = HostsFile {
+            lines: vec![HostsFileLine {
+                is_empty: false,
+                ip: Some("fe80::1%lo0".to_string()),
+                hosts: Some(vec!["localhost".to_string()]),
+                comment: None,
+                unknown: None,
+                original: None,
+                line_ending: None,
+                leading_comments: vec![],
+            }],
+        };
+        assert_eq!(parsed, expected);
+    }
+
+    #[test]
+    fn a_ipv4_host() {
+        let parsed = HostsFile::from_str("127.0.0.1 localhost").unwrap();
+        let expected = HostsFile {
+            lines: vec![HostsFileLine {
+                is_empty: false,
+                ip: Some("127.0.0.1".to_string()),
+                hosts: Some(vec!["localhost".to_string()]),
+                comment: None,
+                unknown: None,
+                original: None,
+                line_ending: None,
+                leading_comments: vec![],
+            }],
+        };
+        assert_eq!(parsed, expected);
+    }
+
+    #[test]
+    fn complex_1() {
+        let parsed = HostsFile::from_str("# A sample host file\n# empty line\n\n127.0.0.1 localhost\n# multiple hosts\n127.0.0.2 host1 host2\n").unwrap();
+        let expected = HostsFile {
+            lines: vec![
+                HostsFileLine::from_comment("# A sample host file"),
+                HostsFileLine::from_comment("# empty line"),
+                HostsFileLine::from_empty(),
+                HostsFileLine {
+                    is_empty: false,
+                    ip: Some("127.0.0.1".to_string()),
+                    hosts: Some(vec!["localhost".to_string()]),
+                    comment: None,
+                    unknown: None,
+                    original: None,
+                    line_ending: None,
+                    leading_comments: vec![],
+                },
+                HostsFileLine::from_comment("# multiple hosts"),
+                HostsFileLine {
+                    is_empty: false,
+                    ip: Some("127.0.0.2".to_string()),
+                    hosts: Some(["host1", "host2"].iter().map(|s| s.to_string()).collect()),
+                    comment: None,
+                    unknown: None,
+                    original: None,
+                    line_ending: None,
+                    leading_comments: vec![],
+                },
+            ],
+        };
+        assert_eq!(parsed, expected);
+    }
+
+    // Serialize
+
+    #[test]
+    fn into_lines_and_back() {
+        let input = "# hosts\n127.0.0.1 localhost\n127.0.0.2 host1 host2\n";
+        let lines = HostsFile::from_str(input).unwrap().into_lines();
+        assert_eq!(lines.len(), 3);
+        let rebuilt: HostsFile = lines.into_iter().filter(|l| l.has_host()).collect();
+        assert_eq!(
+            rebuilt.serialize(),
+            "127.0.0.1 localhost\n127.0.0.2 host1 host2\n"
+        );
+    }
+    #[test]
+    fn into_iterator() {
+        let file = HostsFile::from_str("# hosts\n127.0.0.1 localhost\n").unwrap();
+        let mut borrowed = 0;
+        for line in &file {
+            borrowed += line.hosts().len();
+        }
+        assert_eq!(borrowed, 1);
+        let lines: Vec<HostsFileLine> = file.into_iter().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].is_comment());
+        assert_eq!(lines[1], "127.0.0.1 localhost");
+    }
+    #[test]
+    fn serialize_empty() {
+        let input = "\n";
+        let serialized = HostsFile::from_str(input).unwrap().serialize();
+        assert_eq!(serialized, input);
+    }
+    #[test]
+    fn serialize_a_comment() {
+        let input = "# a comment\n";
+        let serialized = HostsFile::from_str(input).unwrap().serialize();
+        assert_eq!(serialized, input);
+    }
+
+    #[test]
+    fn serialize_no_lines() {
+        let serialized = HostsFile::from_str("").unwrap().serialize();
+        assert_eq!(serialized, "");
+    }
+    #[test]
+    fn serialize_leading_blank_lines() {
+        let input = "\n\n127.0.0.1 localhost\n";
+        let parsed = HostsFile::from_str(input).unwrap();
+        assert_eq!(parsed.lines.len(), 3);
+        assert_eq!(parsed.serialize(), input);
+    }
+    #[test]
+    fn serialize_trailing_blank_lines() {
+        let input = "127.0.0.1 localhost\n\n\n\n";
+        let parsed = HostsFile::from_str(input).unwrap();
+        assert_eq!(parsed.lines.len(), 4);
+        assert_eq!(parsed.serialize(), input);
+    }
+    #[test]
+    fn serialize_tab_before_comment() {
+        let parsed =
+            HostsFile::from_str("127.0.0.1 localhost\t# note\n127.0.0.2\thost\t#tight\n").unwrap();
+        assert_eq!(parsed.lines[0].hosts(), vec!["localhost"]);
+        assert_eq!(parsed.lines[0].comment(), Some("# note".to_string()));
+        assert_eq!(parsed.lines[1].hosts(), vec!["host"]);
+        assert_eq!(
+            parsed.serialize(),
+            "127.0.0.1 localhost # note\n127.0.0.2 host #tight\n"
+        );
+    }
+    #[test]
+    fn serialize_complex_1() {
+        let input = "# A sample host file\n# empty line\n\n127.0.0.1 localhost\n# multiple hosts\n127.0.0.2 host1 host2\n";
+        let serialized = HostsFile::from_str(input).unwrap().serialize();
+        assert_eq!(serialized, input);
+    }
+    #[test]
+    fn as_tuples_complex_1() {
+        let input = "# A sample host file\n# empty line\n\n127.0.0.1 localhost\n# multiple hosts\n127.0.0.2 host1 host2\n";
+        let parsed = HostsFile::from_str(input).unwrap();
+        assert_eq!(
+            parsed.as_tuples(),
+            vec![
+                ("127.0.0.1".to_string(), vec!["localhost".to_string()]),
+                (
+                    "127.0.0.2".to_string(),
+                    vec!["host1".to_string(), "host2".to_string()]
+                ),
+            ]
+        );
+        let parsed = HostsFile::from_str("0:0::1 localhost\n").unwrap();
+        assert_eq!(parsed.as_tuples()[0].0, "0:0::1");
+    }
+    #[test]
+    fn shortest_serialization_complex_1() {
+        let input = "# A sample host file\n# empty line\n\n127.0.0.1 localhost\n# multiple hosts\n127.0.0.2 host1 host2\n";
+        let parsed = HostsFile::from_str(input).unwrap();
+        assert_eq!(
+            parsed.shortest_serialization(),
+            "127.0.0.1 localhost\n127.0.0.2 host1 host2\n"
+        );
+        assert_eq!(parsed.serialize(), input);
+        let parsed = HostsFile::from_str("  10.0.0.1\tdb   cache # primary\n").unwrap();
+        assert_eq!(parsed.shortest_serialization(), "10.0.0.1 db cache\n");
+    }
+
+    // Errors
+
+    #[test]
+    fn parse_error_kind_and_line() {
+        let err = HostsFile::from_str("# ok\n127.0.0.1 localhost\n127.0.0.1\n").unwrap_err();
+        assert_eq!(err.kind(), &ParseErrorKind::InvalidLine);
+        assert_eq!(err.line(), Some(3));
+        assert_eq!(
+            err.to_string(),
+            "Error parsing hosts file: invalid line at line 3"
+        );
+        let err = HostsParser::new()
+            .validate_ips(true)
+            .parse("bogus host\n")
+            .unwrap_err();
+        assert_eq!(err.kind(), &ParseErrorKind::InvalidIp);
+        assert_eq!(err.line(), Some(1));
+    }
+    #[test]
+    fn max_lines() {
+        let parser = HostsParser::new().max_lines(2);
+        parser.parse("# one\n127.0.0.1 localhost\n").unwrap();
+        let err = parser
+            .parse("# one\n127.0.0.1 localhost\n::1 localhost\n")
+            .unwrap_err();
+        assert_eq!(err.kind(), &ParseErrorKind::LimitExceeded);
+        assert_eq!(err.line(), Some(3));
+    }
+    #[test]
+    fn max_bytes() {
+        let parser = HostsParser::new().max_bytes(20);
+        parser.parse("127.0.0.1 localhost\n").unwrap();
+        let err = parser
+            .parse("127.0.0.1 localhost # too long\n")
+            .unwrap_err();
+        assert_eq!(err.kind(), &ParseErrorKind::LimitExceeded);
+        assert_eq!(err.line(), None);
+    }
+    #[test]
+    fn line_continuation() {
+        let input = "# hosts\n10.0.0.1 web \\\r\n  api   cache # services\n127.0.0.1 localhost\n";
+        let parsed = HostsParser::new()
+            .allow_line_continuation(true)
+            .parse(input)
+            .unwrap();
+        assert_eq!(parsed.lines.len(), 3);
+        assert_eq!(parsed.lines[1].hosts(), vec!["web", "api", "cache"]);
+        assert_eq!(
+            parsed.serialize_preserving(),
+            "# hosts\n10.0.0.1 web api cache # services\n127.0.0.1 localhost\n"
+        );
+        assert_eq!(HostsFile::from_str(input).unwrap().lines.len(), 4);
+    }
+    #[test]
+    fn line_continuation_errors_and_eof() {
+        let parser = HostsParser::new().allow_line_continuation(true);
+        let err = parser.parse("# ok\n10.0.0.1 \\\n\\\n").unwrap_err();
+        assert_eq!(err.kind(), &ParseErrorKind::InvalidLine);
+        assert_eq!(err.line(), Some(2));
+        let parsed = parser.parse("10.0.0.1 a\\\nb\\").unwrap();
+        assert_eq!(parsed.serialize(), "10.0.0.1 a b\n");
+    }
+    #[test]
+    fn line_continuation_skips_comments() {
+        let parsed = HostsParser::new()
+            .allow_line_continuation(true)
+            .parse("# path C:\\\n127.0.0.1 localhost\n")
+            .unwrap();
+        assert_eq!(parsed.lines.len(), 2);
+        assert_eq!(parsed.lines[0].comment(), Some("# path C:\\".to_string()));
+        assert_eq!(parsed.count_hosts(), 1);
+        let parsed = HostsParser::new()
+            .allow_line_continuation(true)
+            .parse("127.0.0.1 localhost # C:\\\n10.0.0.1 db\n")
+            .unwrap();
+        assert_eq!(parsed.lines.len(), 2);
+        assert_eq!(parsed.lines[0].comment(), Some("# C:\\".to_string()));
+        assert!(parsed.lookup("db").is_some());
+    }
+    #[test]
+    fn reject_duplicate_hosts() {
+        let parser = HostsParser::new().reject_duplicate_hosts(true);
+        parser
+            .parse("127.0.0.1 localhost localhost\n::1 ip6-localhost\n")
+            .unwrap();
+        let input = "# hosts\n127.0.0.1 localhost\n10.0.0.1 db\n::1 LOCALHOST\n";
+        let err = parser.parse(input).unwrap_err();
+        assert_eq!(
+            err.kind(),
+            &ParseErrorKind::DuplicateHostname("LOCALHOST".to_string())
+        );
+        assert_eq!(err.line(), Some(4));
+        assert_eq!(
+            err.to_string(),
+            "Error parsing hosts file: duplicate hostname LOCALHOST at line 4"
+        );
+        HostsParser::new().parse(input).unwrap();
+    }
+    #[test]
+    fn max_line_bytes() {
+        let parser = HostsParser::new().max_line_bytes(20);
+        parser.parse("# short\r\n127.0.0.1 localhost\r\n").unwrap();
+        let err = parser
+            .parse("# short\n127.0.0.1 localhost local\n::1 localhost\n")
+            .unwrap_err();
+        assert_eq!(err.kind(), &ParseErrorKind::LineTooLong);
+        assert_eq!(err.line(), Some(2));
+        assert_eq!(
+            err.to_string(),
+            "Error parsing hosts file: line too long at line 2"
+        );
+        HostsParser::new()
+            .parse(&format!("# {}\n", "x".repeat(10_000)))
+            .unwrap();
+    }
+
+    // Hostname validation
+
+    #[test]
+    fn validate_hostnames_accepts_valid_names() {
+        let parser = HostsParser::new().validate_hostnames(true);
+        parser
+            .parse("127.0.0.1 localhost example.com a-b.example.com\n")
+            .unwrap();
+    }
+    #[test]
+    fn validate_hostnames_rejects_invalid_names() {
+        let parser = HostsParser::new().validate_hostnames(true);
+        parser
+            .parse("127.0.0.1 -bad.example\n")
+            .expect_err("should fail");
+        parser
+            .parse("127.0.0.1 bad..example\n")
+            .expect_err("should fail");
+    }
+    #[test]
+    fn underscores_rejected_in_strict_mode() {
+        let parser = HostsParser::new().validate_hostnames(true);
+        parser
+            .parse("10.0.0.1 _service._tcp.example.com\n")
+            .expect_err("should fail");
+    }
+    #[test]
+    fn underscores_accepted_when_relaxed() {
+        let parser = HostsParser::new()
+            .validate_hostnames(true)
+            .allow_underscores(true);
+        let parsed = parser
+            .parse("10.0.0.1 _service._tcp.example.com _dmarc.example.com\n")
+            .unwrap();
+        assert_eq!(
+            parsed.lines[0].hosts(),
+            vec!["_service._tcp.example.com", "_dmarc.example.com"]
+        );
+    }
+    #[test]
+    fn underscores_ignored_without_validation() {
+        HostsParser::new()
+            .allow_underscores(false)
+            .parse("10.0.0.1 some_host\n")
+            .unwrap();
+    }
+
+    // Queries
+
+    #[test]
+    fn ip_addr_strips_zone() {
+        let line = HostsFileLine::from_string("fe80::1%lo0 localhost").unwrap();
+        assert_eq!(line.ip_addr(), Some("fe80::1".parse().unwrap()));
+        assert_eq!(HostsFileLine::from_comment("# c").ip_addr(), None);
+    }
+    #[test]
+    fn resolver_map_dual_stack() {
+        let parsed = HostsFile::from_str("127.0.0.1 localhost\n::1 localhost\n").unwrap();
+        let map = parsed.to_resolver_map();
+        assert_eq!(map.len(), 1);
+        assert_eq!(
+            map["localhost"],
+            (Some(Ipv4Addr::LOCALHOST), Some(Ipv6Addr::LOCALHOST))
+        );
+    }
+    #[test]
+    fn resolver_map_last_wins() {
+        let parsed = HostsFile::from_str("10.0.0.1 foo\n10.0.0.2 foo bar\n").unwrap();
+        let map = parsed.to_resolver_map();
+        assert_eq!(map["foo"], (Some(Ipv4Addr::new(10, 0, 0, 2)), None));
+        assert_eq!(map["bar"], (Some(Ipv4Addr::new(10, 0, 0, 2)), None));
+    }
+    #[test]
+    fn resolver_map_mixed_case() {
+        let parsed = HostsFile::from_str(
+            "127.0.0.1 LocalHost
+::1 localhost
+10.0.0.1 DB
+",
+        )
+        .unwrap();
+        let map = parsed.to_resolver_map();
+        assert_eq!(map.len(), 2);
+        assert_eq!(
+            map["localhost"],
+            (Some(Ipv4Addr::LOCALHOST), Some(Ipv6Addr::LOCALHOST))
+        );
+        assert_eq!(map["db"], (Some(Ipv4Addr::new(10, 0, 0, 1)), None));
+    }
+
+    // Comment prefixes
+
+    #[test]
+    fn double_slash_full_line_comment() {
+        let parser = HostsParser::new().comment_prefixes(&["#", "//"]);
+        let parsed = parser.parse("// full line\n# hash line\n").unwrap();
+        let expected = HostsFile {
+            lines: vec![
+                HostsFileLine::from_comment("// full line"),
+                HostsFileLine::from_comment("# hash line"),
+            ],
+        };
+        assert_eq!(parsed, expected);
+    }
+    #[test]
+    fn double_slash_inline_comment() {
+        let parser = HostsParser::new().comment_prefixes(&["#", "//"]);
+        let parsed = parser.parse_line("127.0.0.1 localhost // note").unwrap();
+        assert_eq!(parsed.hosts(), vec!["localhost"]);
+        assert_eq!(parsed.comment(), Some("// note".to_string()));
+        assert_eq!(parsed.to_string(), "127.0.0.1 localhost // note");
+    }
+    #[test]
+    fn empty_comment_prefixes_fall_back_to_hash() {
+        for prefixes in [&[][..], &[""][..], &["", "#"][..]] {
+            let parsed = HostsParser::new()
+                .comment_prefixes(prefixes)
+                .parse("# c\n127.0.0.1 localhost # loop\n")
+                .unwrap();
+            assert!(parsed.lines[0].is_comment());
+            assert_eq!(parsed.lines[1].hosts(), vec!["localhost"]);
+            assert_eq!(parsed.lines[1].comment(), Some("# loop".to_string()));
+        }
+        let parsed = HostsParser::new()
+            .comment_prefixes(&["", "//"])
+            .parse("127.0.0.1 localhost // loop\n")
+            .unwrap();
+        assert_eq!(parsed.count_hosts(), 1);
+    }
+    #[test]
+    fn double_slash_not_a_comment_by_default() {
+        let parsed = HostsFileLine::from_string("127.0.0.1 localhost // note").unwrap();
+        assert_eq!(parsed.hosts(), vec!["localhost", "//", "note"]);
+        assert_eq!(parsed.comment(), None);
+    }
+
+    #[test]
+    fn effective_map_first_wins_per_family() {
+        let parsed =
+            HostsFile::from_str("127.0.0.1 foo\n10.0.0.1 FOO bar\n::1 foo\nfe80::1 foo\n").unwrap();
+        let map = parsed.effective_map();
+        assert_eq!(
+            map["foo"],
+            (Some(Ipv4Addr::LOCALHOST), Some(Ipv6Addr::LOCALHOST))
+        );
+        assert_eq!(map["bar"], (Some(Ipv4Addr::new(10, 0, 0, 1)), None));
+        assert_eq!(map.len(), 2);
+    }
+    #[test]
+    fn subnet_entries_ipv4() {
+        let parsed =
+            HostsFile::from_str("10.1.2.3 a\n11.0.0.1 b\n10.255.0.1 c # c\n127.0.0.1 d\n").unwrap();
+        let found = parsed.subnet_entries("10.0.0.0/8").unwrap();
+        let hosts: Vec<Vec<String>> = found.iter().map(|h| h.hosts.clone()).collect();
+        assert_eq!(hosts, vec![vec!["a"], vec!["c"]]);
+        assert_eq!(found[1].comment, Some("# c".to_string()));
+        assert!(parsed.subnet_entries("192.168.0.0/16").unwrap().is_empty());
+    }
+    #[test]
+    fn subnet_entries_odd_prefix() {
+        let parsed = HostsFile::from_str("192.168.1.200 in\n192.168.1.100 out\n").unwrap();
+        let found = parsed.subnet_entries("192.168.1.128/25").unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].ip, "192.168.1.200");
+    }
+    #[test]
+    fn subnet_entries_ipv6() {
+        let parsed = HostsFile::from_str("fe80::1%lo0 link\n::1 loop\n2001:db8::5 doc\n").unwrap();
+        let found = parsed.subnet_entries("fe80::/10").unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].hosts, vec!["link"]);
+        assert_eq!(
+            parsed.subnet_entries("2001:db8::/32").unwrap()[0].ip,
+            "2001:db8::5"
+        );
+    }
+    #[test]
+    fn subnet_entries_invalid_cidr() {
+        let parsed = HostsFile::from_str("10.0.0.1 a\n").unwrap();
+        parsed
+            .subnet_entries("10.0.0.0")
+            .expect_err("missing prefix");
+        parsed
+            .subnet_entries("10.0.0.0/33")
+            .expect_err("prefix too long");
+        parsed.subnet_entries("nope/8").expect_err("bad address");
+    }
+
+    #[test]
+    fn comment_body() {
+        let line = HostsFileLine::from_comment("# a comment");
+        assert_eq!(line.comment_body(), Some("a comment".to_string()));
+        assert_eq!(line.comment(), Some("# a comment".to_string()));
+        let line = HostsFileLine::from_comment("##doc");
+        assert_eq!(line.comment_body(), Some("doc".to_string()));
+        let line = HostsFileLine::from_string("127.0.0.1 localhost #  spaced").unwrap();
+        assert_eq!(line.comment_body(), Some("spaced".to_string()));
+        assert_eq!(HostsFileLine::from_empty().comment_body(), None);
+    }
+
+    #[test]
+    fn managed_marker() {
+        let parsed = HostsFile::from_str("#!managed-by-tool\n127.0.0.1 localhost\n").unwrap();
+        assert_eq!(parsed.managed_marker(), Some("managed-by-tool".to_string()));
+        let parsed = HostsFile::from_str("# plain\n#!late\n").unwrap();
+        assert_eq!(parsed.managed_marker(), None);
+        assert_eq!(HostsFile::from_str("").unwrap().managed_marker(), None);
+    }
+    #[test]
+    fn is_managed_by() {
+        let parsed = HostsFile::from_str(
+            "127.0.0.1 localhost # managed by devtool\n# managed by devtool v2\n10.0.0.1 db\n",
+        )
+        .unwrap();
+        assert!(parsed.is_managed_by("# managed by devtool"));
+        assert!(parsed.is_managed_by("# managed by devtool v2"));
+        assert!(!parsed.is_managed_by("# managed by othertool"));
+        let mut attached = HostsFile::from_str("# BEGIN sync\n10.0.0.1 db\n").unwrap();
+        attached.attach_leading_comments();
+        assert!(attached.is_managed_by("# BEGIN sync"));
+    }
+    #[test]
+    fn find_orphan_comments() {
+        let parsed = HostsFile::from_str(
+            "# Local development\n# api.dev.example.com points at the VM\n10.0.0.5 api.dev.example.com\n# removed staging.example.com entry\n# 10.0.0.9 old.example.com\n127.0.0.1 localhost\n",
+        )
+        .unwrap();
+        assert_eq!(parsed.find_orphan_comments(), vec![3, 4]);
+    }
+    #[test]
+    fn split_by_marker() {
+        let bundle = HostsFile::from_str(
+            "# bundle\n# ---FILE: base---\n127.0.0.1 localhost\n\n# ---FILE: dev ---\n# dev boxes\n10.0.0.1 api.dev\n",
+        )
+        .unwrap();
+        let parts = bundle.split_by_marker("# ---FILE:");
+        let names: Vec<&str> = parts.iter().map(|(n, _)| n.as_str()).collect();
+        assert_eq!(names, vec!["", "base", "dev"]);
+        assert_eq!(parts[0].1.serialize(), "# bundle\n");
+        assert_eq!(parts[1].1.serialize(), "127.0.0.1 localhost\n\n");
+        assert_eq!(parts[2].1.serialize(), "# dev boxes\n10.0.0.1 api.dev\n");
+        assert_eq!(bundle.split_by_marker("# ===").len(), 1);
+    }
+    #[test]
+    fn managed_region() {
+        let mut parsed = HostsFile::from_str(
+            "127.0.0.1 localhost\n# BEGIN managed\n10.0.0.1 old\n10.0.0.2 older\n# END managed\n# user\n",
+        )
+        .unwrap();
+        assert_eq!(
+            parsed.managed_region("# BEGIN managed", "# END managed"),
+            Some(2..4)
+        );
+        let lines = vec![HostsFileLine::from_host("10.0.0.3", &["new"]).unwrap()];
+        parsed.replace_managed_region("# BEGIN managed", "# END managed", lines);
+        assert_eq!(
+            parsed.serialize(),
+            "127.0.0.1 localhost\n# BEGIN managed\n10.0.0.3 new\n# END managed\n# user\n"
+        );
+    }
+    #[test]
+    fn managed_region_absent() {
+        let parsed = HostsFile::from_str("127.0.0.1 localhost\n# BEGIN managed\n").unwrap();
+        assert_eq!(
+            parsed.managed_region("# BEGIN managed", "# END managed"),
+            None
+        );
+        let mut parsed2 = HostsFile::from_str("127.0.0.1 localhost\n").unwrap();
+        let lines = vec![HostsFileLine::from_host("10.0.0.3", &["new"]).unwrap()];
+        parsed2.replace_managed_region("# BEGIN managed", "# END managed", lines.clone());
+        assert_eq!(
+            parsed2.serialize(),
+            "127.0.0.1 localhost\n# BEGIN managed\n10.0.0.3 new\n# END managed\n"
+        );
+        assert_eq!(
+            parsed2.managed_region("# BEGIN managed", "# END managed"),
+            Some(2..3)
+        );
+        let mut parsed =
+            HostsFile::from_str("127.0.0.1 localhost\n# BEGIN managed\n# user\n").unwrap();
+        parsed.replace_managed_region("# BEGIN managed", "# END managed", lines);
+        assert_eq!(
+            parsed.serialize(),
+            "127.0.0.1 localhost\n# BEGIN managed\n10.0.0.3 new\n# END managed\n# user\n"
+        );
+    }
+    #[test]
+    fn comment_blocks() {
+        let parsed = HostsFile::from_str(
+            "# Header\n# describes\n# the file\n\n127.0.0.1 localhost # inline\n# trailer\n",
+        )
+        .unwrap();
+        let expected = vec![
+            (
+                0,
+                vec![
+                    "Header".to_string(),
+                    "describes".to_string(),
+                    "the file".to_string(),
+                ],
+            ),
+            (5, vec!["trailer".to_string()]),
+        ];
+        assert_eq!(parsed.comment_blocks(), expected);
+    }
+
+    // Edit
+
+    #[test]
+    fn set_comment() {
+        let mut line = HostsFileLine::from_string("127.0.0.1 localhost # old").unwrap();
+        line.set_comment(Some("new"));
+        assert_eq!(line, "127.0.0.1 localhost # new");
+        line.set_comment(None);
+        assert_eq!(line, "127.0.0.1 localhost");
+        let mut line = HostsFileLine::from_empty();
+        line.set_comment(Some("## section"));
+        assert!(line.is_comment());
+        line.set_comment(None);
+        assert!(line.is_empty());
+    }
+    #[test]
+    fn add_hosts() {
+        let mut line = HostsFileLine::from_string("127.0.0.1 localhost # loopback").unwrap();
+        line.add_hosts(&["local", "LOCALHOST", "lo", "lo", "loop"])
+            .unwrap();
+        assert_eq!(line, "127.0.0.1 localhost local lo loop # loopback");
+        assert_eq!(
+            line.add_hosts(&["bad name"]).unwrap_err().kind(),
+            &ParseErrorKind::InvalidHostname
+        );
+        assert_eq!(line.hosts().len(), 4);
+        for mut other in [
+            HostsFileLine::from_comment("# note"),
+            HostsFileLine::from_empty(),
+        ] {
+            assert_eq!(
+                other.add_hosts(&["host"]).unwrap_err().kind(),
+                &ParseErrorKind::InvalidLine
+            );
+        }
+    }
+    #[test]
+    fn with_comment() {
+        let line = HostsFileLine::from_host("127.0.0.1", &["localhost"])
+            .unwrap()
+            .with_comment("loopback");
+        assert_eq!(line, "127.0.0.1 localhost # loopback");
+        let line = HostsFileLine::from_empty().with_comment("# section");
+        assert!(line.is_comment());
+        assert_eq!(line, HostsFileLine::from_comment("# section"));
+    }
+    #[test]
+    fn comment_lines_mut() {
+        let mut file =
+            HostsFile::from_str("# local\n127.0.0.1 localhost # loop\n\n# remote\n").unwrap();
+        for line in file.comment_lines_mut() {
+            let body = line.comment_body().unwrap();
+            line.set_comment(Some(&format!("## {}", body.to_uppercase())));
+        }
+        assert_eq!(
+            file.serialize(),
+            "## LOCAL\n127.0.0.1 localhost # loop\n\n## REMOTE\n"
+        );
+    }
+    #[test]
+    fn find_line_mut() {
+        let mut file = HostsFile::from_str("# hosts\n127.0.0.2 Host1 host2\n").unwrap();
+        file.find_line_mut("HOST1")
+            .unwrap()
+            .set_comment(Some("# edited"));
+        assert_eq!(
+            file.serialize(),
+            "# hosts\n127.0.0.2 Host1 host2 # edited\n"
+        );
+        assert!(file.find_line_mut("hosts").is_none());
+    }
+    #[test]
+    fn from_host() {
+        let line = HostsFileLine::from_host("127.0.0.2", &["host1", "host2"]).unwrap();
+        assert_eq!(line, "127.0.0.2 host1 host2");
+        HostsFileLine::from_host("nope", &["host"]).expect_err("invalid ip");
+        HostsFileLine::from_host("127.0.0.1", &[]).expect_err("no hosts");
+        HostsFileLine::from_host("127.0.0.1", &["two words"]).expect_err("bad host");
+    }
+    #[test]
+    fn from_entries() {
+        let entries = vec![
+            HostsFileHost {
+                ip: "127.0.0.1".to_string(),
+                hosts: vec!["localhost".to_string()],
+                comment: Some("# loopback".to_string()),
+            },
+            HostsFileHost {
+                ip: "10.0.0.2".to_string(),
+                hosts: vec!["db".to_string(), "db.internal".to_string()],
+                comment: None,
+            },
+        ];
+        let file = HostsFile::from_entries(&entries).unwrap();
+        assert_eq!(
+            file.serialize(),
+            "127.0.0.1 localhost # loopback\n10.0.0.2 db db.internal\n"
+        );
+        let invalid = vec![HostsFileHost {
+            ip: "10.0.0.256".to_string(),
+            hosts: vec!["db".to_string()],
+            comment: None,
+        }];
+        HostsFile::from_entries(&invalid).expect_err("invalid ip");
+    }
+    #[test]
+    fn extend_from_str() {
+        let mut file = HostsFile::from_str("127.0.0.1 localhost\n").unwrap();
+        file.extend_from_str("# extra\n10.0.0.1 db\n").unwrap();
+        assert_eq!(file.lines.len(), 3);
+        assert_eq!(
+            file.serialize(),
+            "127.0.0.1 localhost\n# extra\n10.0.0.1 db\n"
+        );
+        file.extend_from_str("10.0.0.2 ok\n10.0.0.3\n")
+            .expect_err("should fail");
+        assert_eq!(file.lines.len(), 3);
+    }
+    #[test]
+    fn add_entry() {
+        let mut file = HostsFile::from_str("# hosts\n").unwrap();
+        file.add_entry("127.0.0.1", &["localhost"], Some("loopback"))
+            .unwrap();
+        file.add_entry("::1", &["localhost"], None).unwrap();
+        assert_eq!(
+            file.serialize(),
+            "# hosts\n127.0.0.1 localhost # loopback\n::1 localhost\n"
+        );
+    }
+    #[test]
+    fn append_comment_and_blank() {
+        let mut file = HostsFile::from_str("").unwrap();
+        file.append_comment("Generated hosts");
+        file.append_blank();
+        file.append_comment("## Loopback");
+        file.add_entry("127.0.0.1", &["localhost"], None).unwrap();
+        file.append_blank();
+        file.append_comment("# Services");
+        file.add_entry("10.0.0.1", &["db"], Some("primary"))
+            .unwrap();
+        assert_eq!(
+            file.serialize(),
+            "# Generated hosts\n\n## Loopback\n127.0.0.1 localhost\n\n# Services\n10.0.0.1 db # primary\n"
+        );
+        assert!(file.lines[0].is_comment());
+        assert!(file.lines[1].is_empty());
+    }
+    #[test]
+    fn add_entry_addr() {
+        let mut file = HostsFile::from_str("").unwrap();
+        let ip = Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 0x1);
+        file.add_entry_addr(IpAddr::V6(ip), &["link"], Some("local"))
+            .unwrap();
+        file.add_entry_addr(IpAddr::V4(Ipv4Addr::LOCALHOST), &["localhost"], None)
+            .unwrap();
+        assert_eq!(
+            file.serialize(),
+            "fe80::1 link # local\n127.0.0.1 localhost\n"
+        );
+        file.add_entry_addr(IpAddr::V4(Ipv4Addr::LOCALHOST), &[], None)
+            .expect_err("no hosts");
+    }
+    #[test]
+    fn insert_line() {
+        let mut file = HostsFile::from_str("127.0.0.1 localhost\n").unwrap();
+        assert!(file.insert_line(0, HostsFileLine::from_comment("# top")));
+        assert!(file.insert_line(2, HostsFileLine::from_comment("# end")));
+        assert!(!file.insert_line(4, HostsFileLine::from_empty()));
+        assert_eq!(file.serialize(), "# top\n127.0.0.1 localhost\n# end\n");
+    }
+    #[test]
+    fn insert_after_host() {
+        let mut file = HostsFile::from_str("# hosts\n127.0.0.1 localhost\n10.0.0.1 db\n").unwrap();
+        let line = HostsFileLine::from_host("127.0.0.2", &["dev.local"]).unwrap();
+        assert!(file.insert_after_host("localhost", line));
+        assert_eq!(
+            file.serialize(),
+            "# hosts\n127.0.0.1 localhost\n127.0.0.2 dev.local\n10.0.0.1 db\n"
+        );
+        assert!(!file.insert_after_host("missing", HostsFileLine::from_empty()));
+        assert_eq!(file.lines.len(), 4);
+    }
+    #[test]
+    fn ensure_localhost() {
+        let mut file = HostsFile::from_str("# hosts\n127.0.0.1 LOCALHOST loopback\n").unwrap();
+        assert_eq!(file.ensure_localhost(), 1);
+        assert_eq!(
+            file.serialize(),
+            "::1 localhost\n# hosts\n127.0.0.1 LOCALHOST loopback\n"
+        );
+        assert_eq!(file.ensure_localhost(), 0);
+        let mut file = HostsFile::from_str("").unwrap();
+        assert_eq!(file.ensure_localhost(), 2);
+        assert_eq!(file.serialize(), "127.0.0.1 localhost\n::1 localhost\n");
+    }
+    #[test]
+    fn replace_line_at() {
+        let mut file = HostsFile::from_str("# hosts\n127.0.0.1 localhost\n").unwrap();
+        let line = HostsFileLine::from_host("::1", &["localhost"]).unwrap();
+        let previous = file.replace_line_at(1, line).unwrap();
+        assert_eq!(previous, "127.0.0.1 localhost");
+        assert_eq!(file.serialize(), "# hosts\n::1 localhost\n");
+    }
+    #[test]
+    fn replace_line_at_out_of_range() {
+        let mut file = HostsFile::from_str("# hosts\n").unwrap();
+        assert!(file
+            .replace_line_at(1, HostsFileLine::from_empty())
+            .is_none());
+        assert_eq!(file.serialize(), "# hosts\n");
+    }
+    #[test]
+    fn swap_lines() {
+        let mut file = HostsFile::from_str("# hosts\n127.0.0.1 localhost\n10.0.0.1 db\n").unwrap();
+        assert!(file.swap_lines(1, 2));
+        assert_eq!(
+            file.serialize(),
+            "# hosts\n10.0.0.1 db\n127.0.0.1 localhost\n"
+        );
+        assert!(file.swap_lines(0, 0));
+        assert_eq!(
+            file.serialize(),
+            "# hosts\n10.0.0.1 db\n127.0.0.1 localhost\n"
+        );
+    }
+    #[test]
+    fn swap_lines_out_of_range() {
+        let mut file = HostsFile::from_str("# hosts\n127.0.0.1 localhost\n").unwrap();
+        assert!(!file.swap_lines(0, 2));
+        assert!(!file.swap_lines(5, 1));
+        assert_eq!(file.serialize(), "# hosts\n127.0.0.1 localhost\n");
+    }
+    #[test]
+    fn normalize_whitespace() {
+        let mut file = HostsFile::from_str(
+            "  #  header\n\t\n127.0.0.1\tlocalhost   loop\t# lo\n  10.0.0.1 \t db  \n",
+        )
+        .unwrap();
+        file.normalize_whitespace();
+        assert_eq!(
+            file.serialize_preserving(),
+            "  #  header\n\t\n127.0.0.1 localhost loop # lo\n10.0.0.1 db\n"
+        );
+    }
+    #[test]
+    fn collapse_duplicate_comments() {
+        let mut file = HostsFile::from_str(
+            "# ads\n#  ads\n0.0.0.0 ads.example\n# ads\n\n# ads\n# other\n# ads\n",
+        )
+        .unwrap();
+        file.collapse_duplicate_comments();
+        assert_eq!(
+            file.serialize(),
+            "# ads\n#  ads\n0.0.0.0 ads.example\n# ads\n\n# ads\n# other\n# ads\n"
+        );
+        let mut file = HostsFile::from_str("## section ##\n## section ##\n10.0.0.1 db\n").unwrap();
+        file.collapse_duplicate_comments();
+        assert_eq!(file.serialize(), "## section ##\n10.0.0.1 db\n");
+    }
+    #[test]
+    fn clear() {
+        let mut file = HostsFile::from_str("# hosts\n127.0.0.1 localhost\n").unwrap();
+        file.clear();
+        assert!(file.lines.is_empty());
+        assert_eq!(file.serialize(), "");
+    }
+    #[test]
+    fn clear_entries() {
+        let mut file = HostsFile::from_str(
+            "# hosts\n127.0.0.1 localhost\n\n## section\n10.0.0.1 db # primary\n",
+        )
+        .unwrap();
+        file.clear_entries();
+        assert_eq!(file.serialize(), "# hosts\n\n## section\n");
+    }
+    #[test]
+    fn clear_entries_keeps_attached_comments() {
+        let input = "# web server\r\n10.0.0.2 web\r\n\n  # db\n10.0.0.1 db\n";
+        let mut file = HostsFile::from_str(input).unwrap();
+        file.attach_leading_comments();
+        file.clear_entries();
+        assert_eq!(file.lines.len(), 3);
+        assert!(file.lines.iter().all(|l| l.leading_comments().is_empty()));
+        assert_eq!(file.serialize_preserving(), "# web server\r\n\n  # db\n");
+    }
+    #[test]
+    fn truncate_entries() {
+        let mut file = HostsFile::from_str(
+            "# blocklist\n0.0.0.0 a.example\n0.0.0.0 b.example\n# tail\n0.0.0.0 c.example\n",
+        )
+        .unwrap();
+        file.truncate_entries(5);
+        assert_eq!(file.count_hosts(), 3);
+        file.truncate_entries(2);
+        assert_eq!(
+            file.serialize(),
+            "# blocklist\n0.0.0.0 a.example\n0.0.0.0 b.example\n# tail\n"
+        );
+        file.truncate_entries(0);
+        assert_eq!(file.serialize(), "# blocklist\n# tail\n");
+    }
+    #[test]
+    fn remove_host() {
+        let mut file =
+            HostsFile::from_str("127.0.0.1 localhost\n10.0.0.1 db # database\n10.0.0.2 DB cache\n")
+                .unwrap();
+        assert_eq!(file.remove_host("db"), 2);
+        assert_eq!(file.serialize(), "127.0.0.1 localhost\n10.0.0.2 cache\n");
+        assert_eq!(file.remove_host("missing"), 0);
+    }
+    #[test]
+    fn remove_host_keeps_comment_on_remaining_hosts() {
+        let mut file = HostsFile::from_str("127.0.0.2 host1 host2 # c\n").unwrap();
+        assert_eq!(file.remove_host("host1"), 1);
+        assert_eq!(file.lines[0].hosts(), vec!["host2"]);
+        assert_eq!(file.lines[0].comment(), Some("# c".to_string()));
+        assert_eq!(file.serialize(), "127.0.0.2 host2 # c\n");
+        assert_eq!(file.remove_host("host2"), 1);
+        assert!(file.lines.is_empty());
+    }
+    #[test]
+    fn set_ip_for() {
+        let mut file = HostsFile::from_str("10.0.0.1 db # primary\n10.0.0.2 web api\n").unwrap();
+        assert_eq!(file.set_ip_for("db", "10.0.0.9").unwrap(), 1);
+        assert_eq!(file.set_ip_for("api", "10.0.0.8").unwrap(), 1);
+        assert_eq!(
+            file.serialize(),
+            "10.0.0.9 db # primary\n10.0.0.2 web\n10.0.0.8 api\n"
+        );
+        file.set_ip_for("db", "bogus").expect_err("invalid ip");
+    }
+    #[test]
+    fn set_ip_for_repeated_name() {
+        let mut file = HostsFile::from_str("10.0.0.1 db DB\n10.0.0.1 db DB web\n").unwrap();
+        assert_eq!(file.set_ip_for("db", "10.0.0.2").unwrap(), 2);
+        let out = file.serialize();
+        assert_eq!(out, "10.0.0.2 db DB\n10.0.0.1 web\n10.0.0.2 db\n");
+        assert_eq!(HostsFile::from_str(&out).unwrap().serialize(), out);
+        let mut file = HostsFile::from_str("10.0.0.1 db DB\n").unwrap();
+        assert_eq!(
+            file.replace_all("10.0.0.1", "10.0.0.3", Some("db"))
+                .unwrap(),
+            1
+        );
+        assert_eq!(file.serialize(), "10.0.0.3 db DB\n");
+    }
+    #[test]
+    fn ensure_mapping() {
+        let input = "127.0.0.1 localhost\n10.0.0.1 db # primary\n";
+        let mut file = HostsFile::from_str(input).unwrap();
+        file.ensure_mapping("DB", "10.0.0.1".parse().unwrap())
+            .unwrap();
+        assert_eq!(file.serialize_preserving(), input);
+        file.ensure_mapping("db", "10.0.0.2".parse().unwrap())
+            .unwrap();
+        file.ensure_mapping("cache", "::1".parse().unwrap())
+            .unwrap();
+        assert_eq!(
+            file.serialize(),
+            "127.0.0.1 localhost\n10.0.0.2 db # primary\n::1 cache\n"
+        );
+        file.ensure_mapping("bad name", "10.0.0.3".parse().unwrap())
+            .expect_err("invalid hostname");
+    }
+    #[test]
+    fn ensure_mapping_collapses_to_one_line() {
+        let input = "10.0.0.1 foo bar\n10.0.0.2 foo\n";
+        let mut file = HostsFile::from_str(input).unwrap();
+        file.ensure_mapping("foo", "10.0.0.1".parse().unwrap())
+            .unwrap();
+        assert_eq!(file.serialize(), "10.0.0.1 foo bar\n");
+        assert_eq!(file.lines[0].original(), Some("10.0.0.1 foo bar"));
+        let mut file =
+            HostsFile::from_str("10.0.0.1 foo bar\n10.0.0.2 foo baz\n::1 FOO\n").unwrap();
+        file.ensure_mapping("foo", "10.0.0.3".parse().unwrap())
+            .unwrap();
+        assert_eq!(
+            file.serialize(),
+            "10.0.0.1 bar\n10.0.0.3 foo\n10.0.0.2 baz\n"
+        );
+        assert_eq!(
+            file.all_ips_for("foo"),
+            vec!["10.0.0.3".parse::<IpAddr>().unwrap()]
+        );
+    }
+    #[test]
+    fn apply_overrides() {
+        let mut file =
+            HostsFile::from_str("# dev\n127.0.0.1 localhost\n10.0.0.1 api web\n").unwrap();
+        let mut overrides = HashMap::new();
+        overrides.insert("api".to_string(), "192.168.1.5".parse().unwrap());
+        overrides.insert("auth".to_string(), "192.168.1.6".parse().unwrap());
+        file.apply_overrides(&overrides).unwrap();
+        assert_eq!(
+            file.serialize(),
+            "# dev\n127.0.0.1 localhost\n10.0.0.1 web\n192.168.1.5 api\n192.168.1.6 auth\n"
+        );
+    }
+    #[test]
+    fn comment_out_host() {
+        let mut file = HostsFile::from_str("127.0.0.1 localhost\n10.0.0.1 db # x\n").unwrap();
+        assert_eq!(file.comment_out_host("db"), 1);
+        assert_eq!(file.serialize(), "127.0.0.1 localhost\n# 10.0.0.1 db # x\n");
+        assert!(file.lines[1].is_comment());
+    }
+    #[test]
+    fn apply_patch() {
+        let mut file =
+            HostsFile::from_str("127.0.0.1 localhost\n10.0.0.1 db\n10.0.0.2 old\n10.0.0.3 web\n")
+                .unwrap();
+        let ops = vec![
+            HostOp::Add {
+                ip: "10.0.0.4".to_string(),
+                hosts: vec!["cache".to_string()],
+            },
+            HostOp::Remove {
+                hostname: "old".to_string(),
+            },
+            HostOp::SetIp {
+                hostname: "db".to_string(),
+                ip: "10.0.0.5".to_string(),
+            },
+            HostOp::Comment {
+                hostname: "web".to_string(),
+            },
+            HostOp::Remove {
+                hostname: "missing".to_string(),
+            },
+        ];
+        assert_eq!(file.apply_patch(&ops).unwrap(), vec![1, 1, 1, 1, 0]);
+        assert_eq!(
+            file.serialize(),
+            "127.0.0.1 localhost\n10.0.0.5 db\n# 10.0.0.3 web\n10.0.0.4 cache\n"
+        );
+    }
+    #[test]
+    fn apply_patch_rolls_back_on_error() {
+        let input = "127.0.0.1 localhost\n10.0.0.1 db\n";
+        let mut file = HostsFile::from_str(input).unwrap();
+        let ops = vec![
+            HostOp::Remove {
+                hostname: "localhost".to_string(),
+            },
+            HostOp::SetIp {
+                hostname: "db".to_string(),
+                ip: "10.0.0.300".to_string(),
+            },
+            HostOp::Add {
+                ip: "10.0.0.4".to_string(),
+                hosts: vec!["cache".to_string()],
+            },
+        ];
+        file.apply_patch(&ops).expect_err("should fail");
+        assert_eq!(file, HostsFile::from_str(input).unwrap());
+        assert_eq!(file.serialize_preserving(), input);
+    }
+
+    #[test]
+    fn set_ip() {
+        let mut line = HostsFileLine::from_string("127.0.0.1 localhost # loop").unwrap();
+        line.set_ip("fe80::1%lo0").unwrap();
+        assert_eq!(line, "fe80::1%lo0 localhost # loop");
+        assert_eq!(line.original(), None);
+    }
+    #[test]
+    fn set_ip_rejects_invalid_address() {
+        let mut line = HostsFileLine::from_string("127.0.0.1 localhost").unwrap();
+        line.set_ip("127.0.0.300").expect_err("should fail");
+        assert_eq!(line.ip(), Some("127.0.0.1".to_string()));
+    }
+    #[test]
+    fn set_ip_rejects_non_host_lines() {
+        HostsFileLine::from_comment("# c")
+            .set_ip("127.0.0.1")
+            .expect_err("should fail");
+        HostsFileLine::from_empty()
+            .set_ip("127.0.0.1")
+            .expect_err("should fail");
+    }
+    #[test]
+    fn rename_ip() {
+        let mut parsed =
+            HostsFile::from_str("10.0.0.1 a\n10.0.0.3 b\n010.0.0.1 c\n# 10.0.0.1 d\n").unwrap();
+        assert_eq!(parsed.rename_ip("10.0.0.1", "10.0.0.2").unwrap(), 1);
+        let mut parsed = HostsFile::from_str("10.0.0.1 a\n10.0.0.3 b\n10.0.0.1 c # x\n").unwrap();
+        assert_eq!(parsed.rename_ip("10.0.0.1", "10.0.0.2").unwrap(), 2);
+        assert_eq!(
+            parsed.serialize(),
+            "10.0.0.2 a\n10.0.0.3 b\n10.0.0.2 c # x\n"
+        );
+    }
+    #[test]
+    fn replace_all_unscoped() {
+        let mut parsed =
+            HostsFile::from_str("10.0.0.1 foo\n10.0.0.1 bar baz\n10.0.0.3 foo\n").unwrap();
+        assert_eq!(parsed.replace_all("10.0.0.1", "10.0.0.2", None).unwrap(), 2);
+        assert_eq!(
+            parsed.serialize(),
+            "10.0.0.2 foo\n10.0.0.2 bar baz\n10.0.0.3 foo\n"
+        );
+    }
+    #[test]
+    fn replace_all_scoped_to_hostname() {
+        let mut parsed =
+            HostsFile::from_str("10.0.0.1 foo\n10.0.0.1 bar baz\n10.0.0.3 foo\n").unwrap();
+        assert_eq!(
+            parsed
+                .replace_all("10.0.0.1", "10.0.0.2", Some("foo"))
+                .unwrap(),
+            1
+        );
+        assert_eq!(
+            parsed
+                .replace_all("10.0.0.1", "10.0.0.2", Some("baz"))
+                .unwrap(),
+            1
+        );
+        assert_eq!(
+            parsed.serialize(),
+            "10.0.0.2 foo\n10.0.0.1 bar\n10.0.0.2 baz\n10.0.0.3 foo\n"
+        );
+        parsed
+            .replace_all("10.0.0.1", "nope", Some("bar"))
+            .expect_err("invalid ip");
+        parsed
+            .replace_all("nope", "10.0.0.2", None)
+            .expect_err("invalid ip");
+    }
+    #[test]
+    fn replace_all_same_ip_is_noop() {
+        let input = "10.0.0.1 foo bar\n::1 baz\n";
+        let mut parsed = HostsFile::from_str(input).unwrap();
+        assert_eq!(parsed.replace_all("10.0.0.1", "10.0.0.1", None).unwrap(), 0);
+        assert_eq!(
+            parsed
+                .replace_all("10.0.0.1", "10.0.0.1", Some("foo"))
+                .unwrap(),
+            0
+        );
+        assert_eq!(parsed.replace_all("::1", "0:0::1", None).unwrap(), 0);
+        assert_eq!(parsed.serialize_preserving(), input);
+    }
+    #[test]
+    fn rename_hostname_on_ip() {
+        let mut file =
+            HostsFile::from_str("127.0.0.1 foo bar\n10.0.0.1 foo\n127.0.0.1 baz FOO\n::1 foo\n")
+                .unwrap();
+        assert_eq!(
+            file.rename_hostname_on_ip("127.0.0.1", "foo", "bar")
+                .unwrap(),
+            2
+        );
+        assert_eq!(
+            file.serialize(),
+            "127.0.0.1 bar\n10.0.0.1 foo\n127.0.0.1 baz bar\n::1 foo\n"
+        );
+        assert_eq!(
+            file.rename_hostname_on_ip("127.0.0.1", "foo", "x").unwrap(),
+            0
+        );
+        file.rename_hostname_on_ip("bogus", "foo", "x")
+            .expect_err("invalid ip");
+        file.rename_hostname_on_ip("::1", "foo", "#x")
+            .expect_err("invalid hostname");
+    }
+    #[test]
+    fn rename_ip_canonicalizes_new_address() {
+        let mut parsed = HostsFile::from_str("::1 a\n").unwrap();
+        assert_eq!(
+            parsed
+                .rename_ip("0:0:0:0:0:0:0:1", "fe80:0::1%lo0")
+                .unwrap(),
+            1
+        );
+        assert_eq!(parsed.serialize(), "fe80::1%lo0 a\n");
+    }
+    #[test]
+    fn rename_ip_rejects_invalid_new_address() {
+        let mut parsed = HostsFile::from_str("10.0.0.1 a\n").unwrap();
+        parsed
+            .rename_ip("10.0.0.1", "10.0.0.256")
+            .expect_err("should fail");
+        assert_eq!(parsed.serialize(), "10.0.0.1 a\n");
+    }
+
+    #[test]
+    fn byte_and_char_len() {
+        let line = HostsFileLine::from_string("127.0.0.1 localhost").unwrap();
+        assert_eq!(line.byte_len(), 19);
+        assert_eq!(line.char_len(), 19);
+        let line = HostsFileLine::from_string("127.0.0.1 localhost # café").unwrap();
+        assert_eq!(line.byte_len(), 27);
+        assert_eq!(line.char_len(), 26);
+    }
+    #[test]
+    fn matches_hostname() {
+        let line = HostsFileLine::from_string("127.0.0.2 host1 Host2 # host3").unwrap();
+        assert!(line.matches_hostname("host1"));
+        assert!(line.matches_hostname("HOST2"));
+        assert!(!line.matches_hostname("host3"));
+        assert!(!HostsFileLine::from_comment("# host1").matches_hostname("host1"));
+        assert!(!HostsFileLine::from_empty().matches_hostname(""));
+    }
+    #[test]
+    fn line_eq_str() {
+        let line = HostsFileLine::from_string("  127.0.0.1\tlocalhost   # loop ").unwrap();
+        assert_eq!(line, "127.0.0.1 localhost # loop");
+        assert!(line == *" 127.0.0.1 localhost # loop\n");
+        assert!(line != "127.0.0.1 localhost");
+    }
+
+    #[test]
+    fn hosts_for_ip_string() {
+        let parsed = HostsFile::from_str(
+            "127.0.0.1 localhost\n127.000.000.001 padded\nfe80::1%lo0 link\nfe80::1 plain\n127.0.0.1 loop\n",
+        )
+        .unwrap();
+        assert_eq!(
+            parsed.hosts_for_ip_string("127.0.0.1"),
+            vec!["localhost", "loop"]
+        );
+        assert_eq!(
+            parsed.hosts_for_ip_string("127.000.000.001"),
+            vec!["padded"]
+        );
+        assert_eq!(parsed.hosts_for_ip_string("fe80::1%lo0"), vec!["link"]);
+        assert!(parsed.hosts_for_ip_string("fe80:0::1").is_empty());
+    }
+    #[test]
+    fn entries_pointing_to() {
+        let parsed = HostsFile::from_str(
+            "# shared\n::1 localhost\n10.0.0.5 db\n0:0::1 ip6-localhost ip6-loopback\n",
+        )
+        .unwrap();
+        let expected = vec![
+            (1, vec!["localhost".to_string()]),
+            (
+                3,
+                vec!["ip6-localhost".to_string(), "ip6-loopback".to_string()],
+            ),
+        ];
+        assert_eq!(parsed.entries_pointing_to("::1"), expected);
+        assert!(parsed.entries_pointing_to("10.0.0.6").is_empty());
+        assert!(parsed.entries_pointing_to("not an ip").is_empty());
+    }
+
+    // Readers
+
+    #[test]
+    fn from_reader() {
+        let input = "# hosts\n127.0.0.1 localhost\n";
+        let parsed = HostsFile::from_reader(input.as_bytes()).unwrap();
+        assert_eq!(parsed, HostsFile::from_str(input).unwrap());
+    }
+    #[test]
+    fn from_reader_parse_error() {
+        match HostsFile::from_reader("127.0.0.1\n".as_bytes()) {
+            Err(HostsError::Parse(_)) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+    #[test]
+    fn roundtrip_ok() {
+        assert!(HostsFile::roundtrip_ok(""));
+        assert!(HostsFile::roundtrip_ok(
+            "# hosts\r\n\n  127.0.0.1\tlocalhost   loop # a  b\n::1 localhost #\n\t## end"
+        ));
+        assert!(!HostsFile::roundtrip_ok("127.0.0.1\n"));
+        assert!(!HostsFile::roundtrip_ok("127.0.0.1 # no hosts\n"));
+    }
+    #[test]
+    fn visit_lines() {
+        let input = "# blocklist\r\n0.0.0.0 ads.example\n\n0.0.0.0 tracker.example\n127.0.0.1\n";
+        let mut entries = 0;
+        let mut errors = vec![];
+        HostsFile::visit_lines(std::io::Cursor::new(input), |line| match line {
+            Ok(line) if line.has_host() => entries += 1,
+            Ok(_) => {}
+            Err(e) => errors.push(e.line()),
+        })
+        .unwrap();
+        assert_eq!(entries, 2);
+        assert_eq!(errors, vec![Some(5)]);
+    }
+    #[cfg(feature = "gzip")]
+    #[test]
+    fn from_reader_gzip() {
+        use flate2::read::GzDecoder;
+        use flate2::write::GzEncoder;
+        use flate2::Compression;
+        use std::io::Write;
+
+        let input = "0.0.0.0 ads.example.com\n0.0.0.0 tracker.example.com\n";
+        let mut encoder = GzEncoder::new(vec![], Compression::default());
+        encoder.write_all(input.as_bytes()).unwrap();
+        let compressed = encoder.finish().unwrap();
+        let parsed = HostsFile::from_reader(GzDecoder::new(&compressed[..])).unwrap();
+        assert_eq!(parsed.serialize(), input);
+    }
+
+    #[test]
+    fn trim_trailing_whitespace() {
+        let mut file = HostsFile {
+            lines: vec![
+                HostsFileLine::from_comment("# header  \t"),
+                HostsFileLine::from_string("127.0.0.1 localhost").unwrap(),
+            ],
+        };
+        assert_eq!(file.serialize(), "# header  \t\n127.0.0.1 localhost\n");
+        file.trim_trailing_whitespace();
+        assert_eq!(file.serialize(), "# header\n127.0.0.1 localhost\n");
+        assert_eq!(file.lines[0].comment(), Some("# header".to_string()));
     }
     #[test]
-    fn a_ipv6_host() {
-        let parsed = HostsFile::from_str("fe80::1%lo0 localhost\n").unwrap();
-        let expected = HostsFile {
-            lines: vec![HostsFileLine {
-                is_empty: false,
-                ip: Some("fe80::1%lo0".to_string()),
-                hosts: Some(vec!["localhost".to_string()]),
-                comment: None,
-            }],
-        };
-        assert_eq!(parsed, expected);
+    fn normalize_comments_keeps_hash_count() {
+        let mut file = HostsFile::from_str(
+            "#   top\n##\t\tsection\n###  sub  heading \n127.0.0.1 localhost ###   inline\n####\n#tight\n",
+        )
+        .unwrap();
+        file.normalize_comments();
+        assert_eq!(
+            file.serialize(),
+            "# top\n## section\n### sub  heading\n127.0.0.1 localhost ### inline\n####\n#tight\n"
+        );
+        assert!(file.lines[0].original().is_none());
+        assert_eq!(file.lines[4].original(), Some("####"));
+    }
+    #[test]
+    fn normalize_comments_attached() {
+        let mut file = HostsFile::from_str("##   web\n10.0.0.1 web\n").unwrap();
+        file.attach_leading_comments();
+        file.normalize_comments();
+        assert_eq!(file.lines[0].leading_comments(), ["## web".to_string()]);
     }
 
     #[test]
-    fn a_ipv4_host() {
-        let parsed = HostsFile::from_str("127.0.0.1 localhost").unwrap();
-        let expected = HostsFile {
-            lines: vec![HostsFileLine {
-                is_empty: false,
-                ip: Some("127.0.0.1".to_string()),
-                hosts: Some(vec!["localhost".to_string()]),
-                comment: None,
-            }],
-        };
-        assert_eq!(parsed, expected);
+    fn field_separator_spaces_only() {
+        let input = "127.0.0.1\tlocalhost\n";
+        HostsParser::new()
+            .field_separator(WhitespaceMode::Any)
+            .parse(input)
+            .unwrap();
+        let parser = HostsParser::new().field_separator(WhitespaceMode::SpacesOnly);
+        let err = parser.parse(input).unwrap_err();
+        assert_eq!(err.kind(), &ParseErrorKind::InvalidLine);
+        parser
+            .parse("\t# tabbed\tcomment\n127.0.0.1 localhost # a\tb\n")
+            .unwrap();
     }
 
+    // Loose mode
+
     #[test]
-    fn complex_1() {
-        let parsed = HostsFile::from_str("# A sample host file\n# empty line\n\n127.0.0.1 localhost\n# multiple hosts\n127.0.0.2 host1 host2\n").unwrap();
+    fn validate_ips() {
+        let parser = HostsParser::new().validate_ips(true);
+        parser
+            .parse("127.0.0.1 localhost\nfe80::1%lo0 localhost\n")
+            .unwrap();
+        parser
+            .parse("localhost 127.0.0.1\n")
+            .expect_err("should fail");
+        parser.parse("127.0.0.256 host\n").expect_err("should fail");
+    }
+    #[test]
+    fn validate_ips_checks_zone() {
+        let parser = HostsParser::new().validate_ips(true);
+        parser.parse("fe80::1%lo0 localhost\n").unwrap();
+        parser
+            .parse("fe80::1% localhost\n")
+            .expect_err("empty zone");
+        parser
+            .parse("fe80::1%lo0%extra localhost\n")
+            .expect_err("double zone");
+        parser
+            .parse("127.0.0.1%lo0 localhost\n")
+            .expect_err("zone on ipv4");
+        HostsParser::new().parse("fe80::1% localhost\n").unwrap();
+    }
+    #[test]
+    fn unknown_line_errors_when_strict() {
+        let input = "127.0.0.1 localhost\n{{GATEWAY_IP}} router\n";
+        HostsParser::new()
+            .validate_ips(true)
+            .parse(input)
+            .expect_err("should fail");
+    }
+    #[test]
+    fn unknown_line_round_trips_when_loose() {
+        let input = "127.0.0.1 localhost\n  {{GATEWAY_IP}}\trouter\n# end\n";
+        let parsed = HostsParser::new()
+            .validate_ips(true)
+            .loose(true)
+            .parse(input)
+            .unwrap();
         let expected = HostsFile {
             lines: vec![
-                HostsFileLine::from_comment("# A sample host file"),
-                HostsFileLine::from_comment("# empty line"),
-                HostsFileLine::from_empty(),
-                HostsFileLine {
-                    is_empty: false,
-                    ip: Some("127.0.0.1".to_string()),
-                    hosts: Some(vec!["localhost".to_string()]),
-                    comment: None,
-                },
-                HostsFileLine::from_comment("# multiple hosts"),
-                HostsFileLine {
-                    is_empty: false,
-                    ip: Some("127.0.0.2".to_string()),
-                    hosts: Some(
-                        vec!["host1", "host2"]
-                            .iter()
-                            .map(|s| s.to_string())
-                            .collect(),
-                    ),
-                    comment: None,
-                },
+                HostsFileLine::from_string("127.0.0.1 localhost").unwrap(),
+                HostsFileLine::from_unknown("  {{GATEWAY_IP}}\trouter"),
+                HostsFileLine::from_comment("# end"),
             ],
         };
         assert_eq!(parsed, expected);
+        assert!(parsed.lines[1].is_unknown());
+        assert!(!parsed.lines[1].has_host());
+        assert_eq!(parsed.serialize(), input);
+    }
+    #[test]
+    fn unknown_lines() {
+        let input = "127.0.0.1 localhost\n%include /etc/hosts.d/*\n# end\n";
+        let parsed = HostsParser::new()
+            .validate_ips(true)
+            .loose(true)
+            .parse(input)
+            .unwrap();
+        assert_eq!(parsed.unknown_lines(), vec![(1, "%include /etc/hosts.d/*")]);
+        assert_eq!(parsed.serialize(), input);
+        assert!(HostsFile::from_str("127.0.0.1 localhost\n")
+            .unwrap()
+            .unknown_lines()
+            .is_empty());
+    }
+    #[test]
+    fn loose_checks_addresses() {
+        let input = "127.0.0.1 localhost\n{{GW}} router\n";
+        let parsed = HostsParser::new().loose(true).parse(input).unwrap();
+        assert_eq!(parsed.unknown_lines(), vec![(1, "{{GW}} router")]);
+        assert_eq!(parsed.serialize(), input);
+        assert!(HostsParser::new()
+            .parse(input)
+            .unwrap()
+            .unknown_lines()
+            .is_empty());
+    }
+    #[test]
+    fn loose_keeps_lines_without_hosts() {
+        let parsed = HostsParser::new().loose(true).parse("127.0.0.1\n").unwrap();
+        assert_eq!(parsed.lines, vec![HostsFileLine::from_unknown("127.0.0.1")]);
     }
 
-    // Serialize
+    #[test]
+    fn iter_hosts() {
+        let parsed =
+            HostsFile::from_str("# c\n127.0.0.2 host1 host2\nbogus host3\n::1 localhost\n")
+                .unwrap();
+        let pairs: Vec<(IpAddr, &str)> = parsed.iter_hosts().collect();
+        assert_eq!(
+            pairs,
+            vec![
+                ("127.0.0.2".parse().unwrap(), "host1"),
+                ("127.0.0.2".parse().unwrap(), "host2"),
+                ("::1".parse().unwrap(), "localhost"),
+            ]
+        );
+        let parsed = HostsFile::from_str("127.0.0.2 host1 host2\n").unwrap();
+        assert_eq!(parsed.iter_hosts().count(), 2);
+    }
 
     #[test]
-    fn serialize_empty() {
-        let input = "\n";
-        let serialized = HostsFile::from_str(input).unwrap().serialize();
-        assert_eq!(serialized, input);
+    fn lowercase_hostnames() {
+        let mut parsed = HostsFile::from_str(
+            "# KEEP Case\n127.0.0.1 LocalHost # Loopback\nFE80::1 Über.Example\n",
+        )
+        .unwrap();
+        parsed.lowercase_hostnames();
+        assert_eq!(
+            parsed.serialize(),
+            "# KEEP Case\n127.0.0.1 localhost # Loopback\nFE80::1 Über.example\n"
+        );
     }
+
     #[test]
-    fn serialize_a_comment() {
-        let input = "# a comment\n";
-        let serialized = HostsFile::from_str(input).unwrap().serialize();
-        assert_eq!(serialized, input);
+    fn stats_complex_1() {
+        let parsed = HostsFile::from_str("# A sample host file\n# empty line\n\n127.0.0.1 localhost\n# multiple hosts\n127.0.0.2 host1 host2\n").unwrap();
+        let expected = HostsStats {
+            total_lines: 6,
+            host_entries: 2,
+            comment_lines: 3,
+            empty_lines: 1,
+            unique_hostnames: 3,
+            unique_ips: 2,
+            duplicate_hostnames: 0,
+        };
+        assert_eq!(parsed.stats(), expected);
+    }
+    #[test]
+    fn stats_duplicates() {
+        let parsed =
+            HostsFile::from_str("127.0.0.1 foo bar\n0:0::1 FOO\n::1 foo baz\n10.0.0.1 bar\n")
+                .unwrap();
+        let stats = parsed.stats();
+        assert_eq!(stats.unique_hostnames, 3);
+        assert_eq!(stats.unique_ips, 3);
+        assert_eq!(stats.duplicate_hostnames, 2);
+    }
+    #[test]
+    fn count_hosts() {
+        let parsed =
+            HostsFile::from_str("# hosts\n127.0.0.1 localhost\n\n127.0.0.2 host1 host2 # c\n")
+                .unwrap();
+        assert_eq!(parsed.count_hosts(), 3);
+        assert_eq!(HostsFile::from_str("# only\n").unwrap().count_hosts(), 0);
     }
+    #[test]
+    fn hostnames_starting_with() {
+        let parsed = HostsFile::from_str(
+            "# app servers\n10.0.0.1 api.dev web.dev\n10.0.0.2 app.dev API.dev\n::1 App.dev\n",
+        )
+        .unwrap();
+        assert_eq!(
+            parsed.hostnames_starting_with("a"),
+            vec!["api.dev", "app.dev"]
+        );
+        assert_eq!(
+            parsed.hostnames_starting_with("AP"),
+            vec!["api.dev", "app.dev"]
+        );
+        assert_eq!(parsed.hostnames_starting_with("web.dev"), vec!["web.dev"]);
+        assert!(parsed.hostnames_starting_with("z").is_empty());
+    }
+    #[test]
+    fn ip_families() {
+        let parsed =
+            HostsFile::from_str("# dual stack\n127.0.0.1 localhost\n::1 localhost\nbogus host\n")
+                .unwrap();
+        assert_eq!(parsed.ip_families(), (1, 1));
+        assert_eq!(HostsFile::from_str("").unwrap().ip_families(), (0, 0));
+    }
+
+    // Zero-copy view
 
     #[test]
-    fn serialize_complex_1() {
-        let input = "# A sample host file\n# empty line\n\n127.0.0.1 localhost\n# multiple hosts\n127.0.0.2 host1 host2\n";
-        let serialized = HostsFile::from_str(input).unwrap().serialize();
-        assert_eq!(serialized, input);
+    fn parse_ref_entries() {
+        let input = "# header\n\n127.0.0.1\tlocalhost  # loop back\n127.0.0.2 host1 host2\n";
+        let parsed = HostsFile::parse_ref(input).unwrap();
+        let entries = parsed.entries();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].ip(), "127.0.0.1");
+        assert_eq!(entries[0].hosts().collect::<Vec<&str>>(), vec!["localhost"]);
+        assert_eq!(entries[0].comment(), Some("# loop back"));
+        assert_eq!(
+            entries[1].hosts().collect::<Vec<&str>>(),
+            vec!["host1", "host2"]
+        );
+        assert_eq!(entries[1].comment(), None);
+        assert_eq!(parsed.lookup("HOST2"), Some("127.0.0.2"));
+        assert!(parsed.contains("localhost"));
+        assert!(!parsed.contains("missing"));
+    }
+    #[test]
+    fn parse_ref_borrows_from_input() {
+        let input = String::from("127.0.0.1 localhost\n10.0.0.1 a b c # note\n");
+        let range = input.as_ptr() as usize..input.as_ptr() as usize + input.len();
+        let parsed = HostsFile::parse_ref(&input).unwrap();
+        for entry in parsed.entries() {
+            assert!(range.contains(&(entry.ip().as_ptr() as usize)));
+            for host in entry.hosts() {
+                assert!(range.contains(&(host.as_ptr() as usize)));
+            }
+        }
+    }
+    #[test]
+    fn parse_ref_rejects_missing_hosts() {
+        HostsFile::parse_ref("127.0.0.1\n").expect_err("should fail");
+        HostsFile::parse_ref("127.0.0.1 # only comment\n").expect_err("should fail");
+    }
+
+    #[test]
+    fn comment_for() {
+        let parsed = HostsFile::from_str(
+            "# localhost lives here\n127.0.0.1 localhost # loopback\n10.0.0.1 db\n",
+        )
+        .unwrap();
+        assert_eq!(
+            parsed.comment_for("localhost"),
+            Some("# loopback".to_string())
+        );
+        assert_eq!(
+            parsed.comment_for("LOCALHOST"),
+            Some("# loopback".to_string())
+        );
+        assert_eq!(parsed.comment_for("db"), None);
+        assert_eq!(parsed.comment_for("missing"), None);
+    }
+
+    // Validation
+
+    #[test]
+    fn validate_clean_file() {
+        let parsed = HostsFile::from_str("# hosts\n127.0.0.1 localhost # loop\n").unwrap();
+        assert_eq!(parsed.validate(), vec![]);
+    }
+    #[test]
+    fn validate_loopback_shadow() {
+        let parsed = HostsFile::from_str(
+            "127.0.0.1 localhost localhost.localdomain app.test\n127.0.0.1 api.example.com\n10.0.0.1 db.example.com\n",
+        )
+        .unwrap();
+        assert_eq!(
+            parsed.validate(),
+            vec![ValidationIssue::LoopbackShadow {
+                hostname: "api.example.com".to_string(),
+                line: 1,
+            }]
+        );
+    }
+    #[test]
+    fn validate_indentation() {
+        let parsed = HostsFile::from_str(
+            "127.0.0.1 localhost\n127.0.0.2\thost2\n  127.0.0.3 host3\n\t# comment\n127.0.0.4 host4 # a\tb\n127.0.0.5 host5\t# c\n",
+        )
+        .unwrap();
+        assert_eq!(
+            parsed.validate(),
+            vec![
+                ValidationIssue::Indentation { line: 1 },
+                ValidationIssue::Indentation { line: 2 },
+                ValidationIssue::Indentation { line: 5 },
+            ]
+        );
+    }
+    #[test]
+    fn validate_null_route_without_comment() {
+        let parsed = HostsFile::from_str(
+            "0.0.0.0 ads.example.com\n0.0.0.0 tracker.example.com # analytics\n:: ads6.example.com\n",
+        )
+        .unwrap();
+        assert_eq!(
+            parsed.validate(),
+            vec![
+                ValidationIssue::NullRouteWithComment { line: 0 },
+                ValidationIssue::NullRouteWithComment { line: 2 },
+            ]
+        );
+    }
+    #[test]
+    fn validate_tab_in_comment() {
+        let parsed = HostsFile::from_str("# a\tb\n127.0.0.1 localhost\n").unwrap();
+        assert_eq!(
+            parsed.validate(),
+            vec![ValidationIssue::TabInComment { line: 0 }]
+        );
+    }
+    #[test]
+    fn validate_comment_too_long() {
+        let input = format!(
+            "# short\n127.0.0.1 localhost # {}\n# {}\n",
+            "x".repeat(254),
+            "y".repeat(255)
+        );
+        let parsed = HostsFile::from_str(&input).unwrap();
+        assert_eq!(
+            parsed.validate(),
+            vec![ValidationIssue::CommentTooLong { line: 2, len: 257 }]
+        );
+        assert_eq!(
+            parsed.validate_with(&ValidationOptions::new().max_comment_len(7)),
+            vec![
+                ValidationIssue::CommentTooLong { line: 1, len: 256 },
+                ValidationIssue::CommentTooLong { line: 2, len: 257 },
+            ]
+        );
+    }
+    #[test]
+    fn line_ending_consistency() {
+        let parsed =
+            HostsFile::from_str("# hosts\r\n127.0.0.1 localhost\n\r\n::1 localhost\n").unwrap();
+        assert_eq!(
+            parsed.line_ending_consistency(),
+            LineEndingReport::Mixed { lf: 2, crlf: 2 }
+        );
+        let parsed = HostsFile::from_str("# hosts\r\n127.0.0.1 localhost").unwrap();
+        assert_eq!(parsed.line_ending_consistency(), LineEndingReport::CrLf(1));
+        assert_eq!(parsed.lines[1], "127.0.0.1 localhost");
+        let parsed = HostsFile::from_str("# hosts\n\n").unwrap();
+        assert_eq!(parsed.line_ending_consistency(), LineEndingReport::Lf(2));
+        assert_eq!(
+            HostsFile::from_str("").unwrap().line_ending_consistency(),
+            LineEndingReport::Unknown
+        );
+    }
+    #[test]
+    fn validate_trailing_whitespace() {
+        let mut parsed =
+            HostsFile::from_str("127.0.0.1 localhost  \n10.0.0.1 db # primary \n10.0.0.2 web\n")
+                .unwrap();
+        assert_eq!(
+            parsed.validate(),
+            vec![
+                ValidationIssue::TrailingWhitespace { line: 0 },
+                ValidationIssue::TrailingWhitespace { line: 1 },
+            ]
+        );
+        parsed.trim_lines();
+        assert!(parsed.validate().is_empty());
+        assert_eq!(
+            parsed.serialize_preserving(),
+            "127.0.0.1 localhost\n10.0.0.1 db # primary\n10.0.0.2 web\n"
+        );
+    }
+
+    #[test]
+    fn primary_host() {
+        let line = HostsFileLine::from_string("127.0.0.2 host1 host2 # c").unwrap();
+        assert_eq!(line.primary_host(), Some("host1"));
+        assert_eq!(HostsFileLine::from_comment("# host1").primary_host(), None);
+        assert_eq!(HostsFileLine::from_empty().primary_host(), None);
+    }
+    #[test]
+    fn ip_literal_hosts() {
+        let line = HostsFileLine::from_string("127.0.0.1 192.168.0.1 realhost ::1").unwrap();
+        assert_eq!(line.ip_literal_hosts(), vec!["192.168.0.1", "::1"]);
+        let line = HostsFileLine::from_string("127.0.0.1 localhost 1.2.3").unwrap();
+        assert!(line.ip_literal_hosts().is_empty());
+        assert!(HostsFileLine::from_comment("# 10.0.0.1 db")
+            .ip_literal_hosts()
+            .is_empty());
+    }
+    #[test]
+    fn as_commented_entry() {
+        let disabled =
+            HostsFileLine::from_string("# 127.0.0.1 localhost local # loopback").unwrap();
+        assert!(disabled.is_comment());
+        let entry = disabled.as_commented_entry().unwrap();
+        assert_eq!(entry.ip, "127.0.0.1");
+        assert_eq!(entry.hosts, vec!["localhost", "local"]);
+        assert_eq!(entry.comment, Some("# loopback".to_string()));
+        assert!(HostsFileLine::from_string("# just a note")
+            .unwrap()
+            .as_commented_entry()
+            .is_none());
+        assert!(HostsFileLine::from_string("# 127.0.0.1")
+            .unwrap()
+            .as_commented_entry()
+            .is_none());
+        assert!(
+            HostsFileLine::from_string("127.0.0.1 localhost # 10.0.0.1 db")
+                .unwrap()
+                .as_commented_entry()
+                .is_none()
+        );
+    }
+    #[test]
+    fn host_entry_ip_helpers() {
+        let entry = HostsFileLine::from_string("0:0::1 localhost")
+            .unwrap()
+            .host_entry()
+            .unwrap();
+        assert_eq!(entry.ip_addr(), Some(IpAddr::V6(Ipv6Addr::LOCALHOST)));
+        assert!(entry.matches_ip(&IpAddr::V6(Ipv6Addr::LOCALHOST)));
+        assert!(!entry.matches_ip(&IpAddr::V4(Ipv4Addr::LOCALHOST)));
+        let bogus = HostsFileHost {
+            ip: "bogus".to_string(),
+            hosts: vec!["x".to_string()],
+            comment: None,
+        };
+        assert_eq!(bogus.ip_addr(), None);
+        assert!(!bogus.matches_ip(&IpAddr::V4(Ipv4Addr::LOCALHOST)));
+    }
+    #[test]
+    fn first_and_last_entry() {
+        let parsed = HostsFile::from_str("# A sample host file\n# empty line\n\n127.0.0.1 localhost\n# multiple hosts\n127.0.0.2 host1 host2\n").unwrap();
+        assert_eq!(*parsed.first_entry().unwrap(), "127.0.0.1 localhost");
+        assert_eq!(*parsed.last_entry().unwrap(), "127.0.0.2 host1 host2");
+        let parsed = HostsFile::from_str("# only comments\n").unwrap();
+        assert!(parsed.first_entry().is_none());
+        assert!(parsed.last_entry().is_none());
+    }
+    #[test]
+    fn entry_at() {
+        let mut parsed = HostsFile::from_str("# hosts\n127.0.0.1 localhost\n").unwrap();
+        assert!(parsed.entry_at(0).unwrap().is_comment());
+        assert_eq!(*parsed.entry_at(1).unwrap(), "127.0.0.1 localhost");
+        assert!(parsed.entry_at(2).is_none());
+        parsed.entry_at_mut(1).unwrap().set_ip("::1").unwrap();
+        assert_eq!(parsed.serialize(), "# hosts\n::1 localhost\n");
+        assert!(parsed.entry_at_mut(2).is_none());
+    }
+    #[test]
+    fn find_line() {
+        let parsed = HostsFile::from_str(
+            "# ::1 old\n127.0.0.1 localhost\n::1 localhost\nfe80::1%lo0 link\n",
+        )
+        .unwrap();
+        let found = parsed.find_line(|l| l.ip_addr().map_or(false, |ip| ip.is_ipv6()));
+        assert_eq!(*found.unwrap(), "::1 localhost");
+        assert!(parsed.find_line(|l| l.is_unknown()).is_none());
+    }
+    #[test]
+    fn lines_matching() {
+        let parsed = HostsFile::from_str(
+            "# router config\n192.168.1.1 router gateway\n192.168.1.2 nas router-backup\n192.168.1.3 printer\n",
+        )
+        .unwrap();
+        let found =
+            parsed.lines_matching(|l| l.hosts().first().map_or(false, |h| h.contains("router")));
+        assert_eq!(found.len(), 1);
+        assert_eq!(*found[0], "192.168.1.1 router gateway");
+    }
+    #[test]
+    fn lookup() {
+        let parsed = HostsFile::from_str("127.0.0.1 localhost\n::1 localhost\n").unwrap();
+        assert_eq!(
+            parsed.lookup("LocalHost"),
+            Some("127.0.0.1".parse().unwrap())
+        );
+        assert_eq!(parsed.first_ip_for("localhost"), parsed.lookup("localhost"));
+        assert_eq!(parsed.lookup("missing"), None);
+    }
+    #[test]
+    fn case_sensitive_lookup() {
+        let parsed = HostsFile::from_str("127.0.0.1 localhost\n").unwrap();
+        assert_eq!(parsed.lookup_cs("LocalHost", true), None);
+        assert_eq!(
+            parsed.lookup_cs("LocalHost", false),
+            Some("127.0.0.1".parse().unwrap())
+        );
+        assert!(parsed.contains_host("LocalHost"));
+        assert!(!parsed.contains_host_cs("LocalHost", true));
+        assert!(parsed.contains_host_cs("localhost", true));
+        assert!(!parsed.contains_host("missing"));
+    }
+    #[test]
+    fn contains_ip() {
+        let parsed =
+            HostsFile::from_str("# 10.0.0.1 old\n127.0.0.1 localhost\n::1 localhost\n").unwrap();
+        assert!(parsed.contains_ip("127.0.0.1"));
+        assert!(parsed.contains_ip("0:0:0:0:0:0:0:1"));
+        assert!(!parsed.contains_ip("10.0.0.1"));
+        assert!(!parsed.contains_ip("not an ip"));
+    }
+    #[test]
+    fn contains_exact_mapping() {
+        let parsed = HostsFile::from_str("127.0.0.1 localhost\n::1 ip6-localhost\n").unwrap();
+        assert!(parsed.contains_exact_mapping("127.0.0.1", "LocalHost"));
+        assert!(parsed.contains_exact_mapping("0::1", "ip6-localhost"));
+        assert!(!parsed.contains_exact_mapping("127.0.0.2", "localhost"));
+        assert!(!parsed.contains_exact_mapping("::1", "localhost"));
+        assert!(!parsed.contains_exact_mapping("bogus", "localhost"));
+    }
+    #[test]
+    fn all_ips_for() {
+        let parsed =
+            HostsFile::from_str("10.0.0.1 foo\n10.0.0.2 bar\n10.0.0.3 foo baz\n10.0.0.1 foo\n")
+                .unwrap();
+        let expected: Vec<IpAddr> = vec!["10.0.0.1".parse().unwrap(), "10.0.0.3".parse().unwrap()];
+        assert_eq!(parsed.all_ips_for("foo"), expected);
+        assert!(parsed.all_ips_for("missing").is_empty());
+    }
+
+    #[test]
+    fn diff_text() {
+        let before = HostsFile::from_str("# hosts\n127.0.0.1 localhost\n10.0.0.1 db\n").unwrap();
+        let after = HostsFile::from_str("# hosts\n127.0.0.1 localhost\n10.0.0.2 db\n").unwrap();
+        assert_eq!(
+            before.diff_text(&after),
+            "--- a\n+++ b\n@@ -1,3 +1,3 @@\n # hosts\n 127.0.0.1 localhost\n-10.0.0.1 db\n+10.0.0.2 db\n"
+        );
+        assert_eq!(before.diff_text(&before.clone()), "");
+    }
+    #[test]
+    fn diff_text_insertions_and_deletions() {
+        let before = HostsFile::from_str("10.0.0.1 a\n10.0.0.2 b\n10.0.0.3 c\n").unwrap();
+        let after = HostsFile::from_str("10.0.0.1 a\n10.0.0.3 c\n10.0.0.4 d\n").unwrap();
+        assert_eq!(
+            before.diff_text(&after),
+            "--- a\n+++ b\n@@ -1,3 +1,3 @@\n 10.0.0.1 a\n-10.0.0.2 b\n 10.0.0.3 c\n+10.0.0.4 d\n"
+        );
+    }
+    // Applies a `diff_text` output to `before`, checking every removed and context line.
+    fn apply_diff(before: &str, diff: &str) -> String {
+        let a: Vec<&str> = before.lines().collect();
+        let mut out: Vec<&str> = vec![];
+        let mut i = 0;
+        for line in diff.lines().skip(2) {
+            if let Some(header) = line.strip_prefix("@@ -") {
+                let range = header.split(' ').next().unwrap();
+                let (start, len) = range.split_once(',').unwrap();
+                let start: usize = start.parse().unwrap();
+                let start = if len == "0" { start } else { start - 1 };
+                out.extend_from_slice(&a[i..start]);
+                i = start;
+            } else if let Some(added) = line.strip_prefix('+') {
+                out.push(added);
+            } else {
+                assert_eq!(a[i], &line[1..]);
+                if line.starts_with(' ') {
+                    out.push(a[i]);
+                }
+                i += 1;
+            }
+        }
+        out.extend_from_slice(&a[i..]);
+        out.iter().map(|l| format!("{}\n", l)).collect()
+    }
+    #[test]
+    fn diff_text_reconstructs_both_sides() {
+        let before: String = (0..40)
+            .map(|i| format!("10.0.{}.{} h{}\n", i % 3, i % 7, i % 5))
+            .collect();
+        let after: String = (0..35)
+            .map(|i| format!("10.0.{}.{} h{}\n", i % 4, i % 7, i % 5))
+            .collect();
+        let diff = HostsFile::from_str(&before)
+            .unwrap()
+            .diff_text(&HostsFile::from_str(&after).unwrap());
+        assert_eq!(apply_diff(&before, &diff), after);
+        // Only lines outside a longest common subsequence are removed or added.
+        let a: Vec<&str> = before.lines().collect();
+        let b: Vec<&str> = after.lines().collect();
+        let mut lcs = vec![vec![0; b.len() + 1]; a.len() + 1];
+        for i in 0..a.len() {
+            for j in 0..b.len() {
+                lcs[i + 1][j + 1] = if a[i] == b[j] {
+                    lcs[i][j] + 1
+                } else {
+                    lcs[i][j + 1].max(lcs[i + 1][j])
+                };
+            }
+        }
+        let body = diff.lines().skip(2).filter(|l| !l.starts_with("@@"));
+        let removed = body.clone().filter(|l| l.starts_with('-')).count();
+        let added = body.filter(|l| l.starts_with('+')).count();
+        assert_eq!(removed, a.len() - lcs[a.len()][b.len()]);
+        assert_eq!(added, b.len() - lcs[a.len()][b.len()]);
+    }
+    #[test]
+    fn diff_text_separate_hunks() {
+        let before: String = (1..=20).map(|i| format!("10.0.0.{} h{}\n", i, i)).collect();
+        let after = before
+            .replace("10.0.0.2 h2\n", "10.0.0.2 two\n")
+            .replace("10.0.0.18 h18\n", "");
+        let diff = HostsFile::from_str(&before)
+            .unwrap()
+            .diff_text(&HostsFile::from_str(&after).unwrap());
+        assert_eq!(
+            diff,
+            "--- a\n+++ b\n@@ -1,5 +1,5 @@\n 10.0.0.1 h1\n-10.0.0.2 h2\n+10.0.0.2 two\n 10.0.0.3 h3\n 10.0.0.4 h4\n 10.0.0.5 h5\n@@ -15,6 +15,5 @@\n 10.0.0.15 h15\n 10.0.0.16 h16\n 10.0.0.17 h17\n-10.0.0.18 h18\n 10.0.0.19 h19\n 10.0.0.20 h20\n"
+        );
+        assert_eq!(apply_diff(&before, &diff), after);
+    }
+    #[test]
+    fn diff_text_empty_side() {
+        let empty = HostsFile::from_str("").unwrap();
+        let one = HostsFile::from_str("10.0.0.1 a\n").unwrap();
+        assert_eq!(
+            empty.diff_text(&one),
+            "--- a\n+++ b\n@@ -0,0 +1,1 @@\n+10.0.0.1 a\n"
+        );
+        assert_eq!(
+            one.diff_text(&empty),
+            "--- a\n+++ b\n@@ -1,1 +0,0 @@\n-10.0.0.1 a\n"
+        );
+    }
+    #[test]
+    fn diff_text_large_file() {
+        let before: String = (0..100_000)
+            .map(|i| format!("0.0.0.0 ads{}.example\n", i))
+            .collect();
+        let after = before.replace("0.0.0.0 ads500.example\n", "0.0.0.0 ads500.example.org\n");
+        let diff = HostsFile::from_str(&before)
+            .unwrap()
+            .diff_text(&HostsFile::from_str(&after).unwrap());
+        assert_eq!(
+            diff,
+            "--- a\n+++ b\n@@ -498,7 +498,7 @@\n 0.0.0.0 ads497.example\n 0.0.0.0 ads498.example\n 0.0.0.0 ads499.example\n-0.0.0.0 ads500.example\n+0.0.0.0 ads500.example.org\n 0.0.0.0 ads501.example\n 0.0.0.0 ads502.example\n 0.0.0.0 ads503.example\n"
+        );
+        assert_eq!(apply_diff(&before, &diff), after);
+    }
+    #[test]
+    fn to_dnsmasq() {
+        let input = "# blocked\n0.0.0.0 ads.example.com # ads\n\n127.0.0.2 host1 host2\n";
+        let parsed = HostsFile::from_str(input).unwrap();
+        assert_eq!(
+            parsed.to_dnsmasq(),
+            "address=/ads.example.com/0.0.0.0\naddress=/host1/127.0.0.2\naddress=/host2/127.0.0.2\n"
+        );
+    }
+
+    #[test]
+    fn to_json() {
+        let input = "# header\n127.0.0.1 localhost # say \"hi\" \\o/\n127.0.0.2 host1 host2\n";
+        let parsed = HostsFile::from_str(input).unwrap();
+        assert_eq!(
+            parsed.to_json(),
+            r##"[{"ip":"127.0.0.1","hosts":["localhost"],"comment":"# say \"hi\" \\o/"},{"ip":"127.0.0.2","hosts":["host1","host2"],"comment":null}]"##
+        );
+        assert_eq!(HostsFile::from_str("# none\n").unwrap().to_json(), "[]");
+    }
+
+    #[cfg(feature = "idna")]
+    #[test]
+    fn normalize_idn() {
+        let mut parsed = HostsFile::from_str("10.0.0.1 bücher.example plain.example\n").unwrap();
+        assert_eq!(
+            parsed.lookup_idn("xn--bcher-kva.example"),
+            Some("10.0.0.1".parse().unwrap())
+        );
+        parsed.normalize_idn();
+        assert_eq!(
+            parsed.serialize(),
+            "10.0.0.1 xn--bcher-kva.example plain.example\n"
+        );
+        assert_eq!(
+            parsed.lookup_idn("Bücher.example"),
+            Some("10.0.0.1".parse().unwrap())
+        );
+    }
+    #[cfg(feature = "idna")]
+    #[test]
+    fn normalize_idn_keeps_hash_names() {
+        let mut parsed = HostsParser::new()
+            .comment_prefixes(&["//"])
+            .parse("10.0.0.1 #b bücher.example // c\n")
+            .unwrap();
+        parsed.normalize_idn();
+        assert_eq!(
+            parsed.serialize(),
+            "10.0.0.1 #b xn--bcher-kva.example // c\n"
+        );
+    }
+    #[test]
+    fn sort_by_domain() {
+        let mut parsed = HostsFile::from_str(
+            "# dev\n10.0.0.9 x.test\n10.0.0.3 b.example.com\n10.0.0.1 a.example.com\n10.0.0.4 A.example.com\n\n10.0.0.2 example.com\n",
+        )
+        .unwrap();
+        parsed.sort_by_domain();
+        assert_eq!(
+            parsed.serialize(),
+            "# dev\n10.0.0.1 a.example.com\n10.0.0.4 A.example.com\n10.0.0.3 b.example.com\n10.0.0.9 x.test\n\n10.0.0.2 example.com\n"
+        );
+    }
+    #[test]
+    fn sort_by_domain_keeps_sections() {
+        let input = "# web\n10.0.0.1 z.example.com\n# db\n10.0.0.2 a.example.com\n";
+        let mut parsed = HostsFile::from_str(input).unwrap();
+        parsed.sort_by_domain();
+        assert_eq!(parsed.serialize(), input);
+    }
+    #[test]
+    fn sort_hosts_within_lines() {
+        let mut parsed =
+            HostsFile::from_str("# c\n127.0.0.2 charlie alpha Bravo # x\n127.0.0.1 localhost\n")
+                .unwrap();
+        parsed.sort_hosts_within_lines(false);
+        assert_eq!(
+            parsed.serialize(),
+            "# c\n127.0.0.2 alpha Bravo charlie # x\n127.0.0.1 localhost\n"
+        );
+        assert!(parsed.lines[2].original().is_some());
+    }
+    #[test]
+    fn sort_hosts_within_lines_pinned() {
+        let mut parsed = HostsFile::from_str("127.0.0.2 charlie alpha bravo\n").unwrap();
+        parsed.sort_hosts_within_lines(true);
+        assert_eq!(parsed.serialize(), "127.0.0.2 charlie alpha bravo\n");
+        let mut parsed = HostsFile::from_str("127.0.0.2 charlie delta bravo\n").unwrap();
+        parsed.sort_hosts_within_lines(true);
+        assert_eq!(parsed.serialize(), "127.0.0.2 charlie bravo delta\n");
+    }
+    #[test]
+    fn map_hostnames() {
+        let mut parsed = HostsFile::from_str("# c\n127.0.0.1 api db # staging\n").unwrap();
+        parsed.map_hostnames(|h| format!("{}.local", h)).unwrap();
+        assert_eq!(
+            parsed.serialize(),
+            "# c\n127.0.0.1 api.local db.local # staging\n"
+        );
+    }
+    #[test]
+    fn map_hostnames_rejects_invalid_names() {
+        let input = "127.0.0.1 api db\n10.0.0.1 web\n";
+        let mut parsed = HostsFile::from_str(input).unwrap();
+        for bad in ["", "a b", "#web"] {
+            let err = parsed
+                .map_hostnames(|h| {
+                    if h == "web" {
+                        bad.to_string()
+                    } else {
+                        h.to_uppercase()
+                    }
+                })
+                .unwrap_err();
+            assert_eq!(err.kind(), &ParseErrorKind::InvalidHostname);
+            assert_eq!(parsed.serialize_preserving(), input);
+        }
+    }
+    #[test]
+    fn map_hostnames_dedupes() {
+        let mut parsed = HostsFile::from_str("127.0.0.1 web.dev web.test api.dev\n").unwrap();
+        parsed
+            .map_hostnames(|h| h.split('.').next().unwrap().to_string())
+            .unwrap();
+        assert_eq!(parsed.serialize(), "127.0.0.1 web api\n");
+    }
+
+    // Preserving serialization
+
+    #[test]
+    fn original_is_retained() {
+        let line = HostsFileLine::from_string("  127.0.0.1\tlocalhost").unwrap();
+        assert_eq!(line.original(), Some("  127.0.0.1\tlocalhost"));
+        assert_eq!(
+            line,
+            HostsFileLine::from_string("127.0.0.1 localhost").unwrap()
+        );
+        assert_eq!(HostsFileLine::from_comment("# c").original(), None);
+    }
+    #[test]
+    fn serialize_preserving_untouched_file() {
+        let input = "  # indented\n127.0.0.1\tlocalhost   # loop\n\t\n  # doc\r\n10.0.0.1   db\n";
+        let mut parsed = HostsFile::from_str(input).unwrap();
+        assert_eq!(parsed.serialize_preserving(), input);
+        parsed.attach_leading_comments();
+        assert_eq!(parsed.serialize_preserving(), input);
+    }
+    #[test]
+    fn write_preserving_only_rewrites_mutated_lines() {
+        let input =
+            "  # indented\n127.0.0.1\tlocalhost   # loop\n10.0.0.1   db  # x\n\n10.0.0.9\tcache\n";
+        let mut parsed = HostsFile::from_str(input).unwrap();
+        assert_eq!(parsed.rename_ip("10.0.0.1", "10.0.0.2").unwrap(), 1);
+        let path = std::env::temp_dir().join(format!(
+            "hosts-parser-write-preserving-{}",
+            std::process::id()
+        ));
+        parsed.write_preserving(&path).unwrap();
+        let written = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let before: Vec<&str> = input.lines().collect();
+        let after: Vec<&str> = written.lines().collect();
+        assert_eq!(after.len(), before.len());
+        for (i, (b, a)) in before.iter().zip(after.iter()).enumerate() {
+            if i == 2 {
+                assert_eq!(*a, "10.0.0.2 db # x");
+            } else {
+                assert_eq!(a, b);
+            }
+        }
+    }
+    #[test]
+    fn serialize_preserving_line_endings() {
+        let input = "# hosts\r\n127.0.0.1  localhost\r\n10.0.0.1 db";
+        let mut parsed = HostsFile::from_str(input).unwrap();
+        assert_eq!(parsed.serialize_preserving(), input);
+        parsed.lines[1].set_ip("::1").unwrap();
+        assert_eq!(
+            parsed.serialize_preserving(),
+            "# hosts\r\n::1 localhost\r\n10.0.0.1 db"
+        );
+        parsed.append_blank();
+        assert_eq!(
+            parsed.serialize_preserving(),
+            "# hosts\r\n::1 localhost\r\n10.0.0.1 db\n\n"
+        );
+    }
+    #[test]
+    fn update_managed_block() {
+        let input = "# user entries\r\n127.0.0.1\tlocalhost   # keep\r\n# BEGIN docker\r\n10.0.0.1 old\r\n# END docker\r\n  # user trailer\r\n";
+        let path =
+            std::env::temp_dir().join(format!("hosts-parser-managed-block-{}", std::process::id()));
+        std::fs::write(&path, input).unwrap();
+        let lines = vec![
+            HostsFileLine::from_host("172.17.0.2", &["web"]).unwrap(),
+            HostsFileLine::from_host("172.17.0.3", &["db"]).unwrap(),
+        ];
+        HostsFile::update_managed_block(&path, "# BEGIN docker", "# END docker", lines).unwrap();
+        let written = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            written,
+            "# user entries\r\n127.0.0.1\tlocalhost   # keep\r\n# BEGIN docker\r\n172.17.0.2 web\r\n172.17.0.3 db\r\n# END docker\r\n  # user trailer\r\n"
+        );
+    }
+    #[test]
+    fn update_managed_block_crlf_from_string() {
+        let path = std::env::temp_dir().join(format!(
+            "hosts-parser-managed-block-crlf-{}",
+            std::process::id()
+        ));
+        std::fs::write(&path, "127.0.0.1 localhost\r\n").unwrap();
+        let lines = vec![
+            HostsFileLine::from_string("10.0.0.1 web").unwrap(),
+            HostsFileLine::from_string("10.0.0.2  db").unwrap(),
+        ];
+        HostsFile::update_managed_block(&path, "# BEGIN", "# END", lines).unwrap();
+        let written = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            written,
+            "127.0.0.1 localhost\r\n# BEGIN\r\n10.0.0.1 web\r\n10.0.0.2  db\r\n# END\r\n"
+        );
+    }
+    #[cfg(unix)]
+    #[test]
+    fn update_managed_block_follows_symlink() {
+        let dir = std::env::temp_dir().join(format!(
+            "hosts-parser-managed-symlink-{}",
+            std::process::id()
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        let target = dir.join("hosts");
+        let link = dir.join("link");
+        std::fs::write(&target, "127.0.0.1 localhost\n").unwrap();
+        std::os::unix::fs::symlink(&target, &link).unwrap();
+        let lines = vec![HostsFileLine::from_host("10.0.0.1", &["web"]).unwrap()];
+        HostsFile::update_managed_block(&link, "# BEGIN", "# END", lines).unwrap();
+        let is_link = std::fs::symlink_metadata(&link)
+            .unwrap()
+            .file_type()
+            .is_symlink();
+        let written = std::fs::read_to_string(&target).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(is_link);
+        assert_eq!(
+            written,
+            "127.0.0.1 localhost\n# BEGIN\n10.0.0.1 web\n# END\n"
+        );
+    }
+    #[test]
+    fn update_managed_block_missing_file() {
+        let path = std::env::temp_dir().join("hosts-parser-managed-block-missing");
+        match HostsFile::update_managed_block(&path, "# BEGIN", "# END", vec![]) {
+            Err(HostsError::Io(_)) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    // Leading comments
+
+    #[test]
+    fn attach_leading_comments() {
+        let input =
+            "# header\n\n# web server\n10.0.0.2 web\n# db\n# primary\n10.0.0.1 db\n# trailing\n";
+        let mut parsed = HostsFile::from_str(input).unwrap();
+        parsed.attach_leading_comments();
+        assert_eq!(parsed.lines.len(), 5);
+        assert_eq!(parsed.lines[2].leading_comments(), ["# web server"]);
+        assert_eq!(parsed.lines[3].leading_comments(), ["# db", "# primary"]);
+        assert_eq!(parsed.lines[2], "10.0.0.2 web");
+        assert_eq!(parsed.lines[2].to_string(), "10.0.0.2 web");
+        assert_eq!(parsed.lines[2].byte_len(), 12);
+        assert_eq!(parsed.lines[2].char_len(), 12);
+        assert_eq!(parsed.serialize(), input);
+        assert_eq!(parsed.serialize_preserving(), input);
+        parsed.detach_leading_comments();
+        assert_eq!(parsed, HostsFile::from_str(input).unwrap());
+    }
+    #[test]
+    fn sorting_keeps_leading_comments_with_entry() {
+        let mut parsed =
+            HostsFile::from_str("# header\n\n# web server\n10.0.0.2 web\n10.0.0.1 db\n").unwrap();
+        parsed.attach_leading_comments();
+        parsed.sort_entries_by(|a, b| a.ip_addr().cmp(&b.ip_addr()));
+        assert_eq!(
+            parsed.serialize(),
+            "# header\n\n10.0.0.1 db\n# web server\n10.0.0.2 web\n"
+        );
+    }
+    #[test]
+    fn line_apis_ignore_leading_comments() {
+        let mut parsed = HostsFile::from_str("# doc\n127.0.0.1 localhost\n").unwrap();
+        parsed.attach_leading_comments();
+        let line = &parsed.lines[0];
+        assert_eq!(*line, "127.0.0.1 localhost");
+        assert_eq!(line.to_string(), "127.0.0.1 localhost");
+        assert_eq!(line.byte_len(), 19);
+        assert_eq!(line.char_len(), 19);
+        parsed.lines[0].set_ip("::1").unwrap();
+        assert_eq!(parsed.serialize(), "# doc\n::1 localhost\n");
+        assert_eq!(parsed.serialize_preserving(), "# doc\n::1 localhost\n");
+    }
+    #[test]
+    fn attached_comments_round_trip() {
+        let input = "  # doc\r\n#\tmore\n10.0.0.1  db\r\n# trailing";
+        let mut parsed = HostsFile::from_str(input).unwrap();
+        parsed.attach_leading_comments();
+        assert_eq!(parsed.lines[0].leading_comments(), ["# doc", "#\tmore"]);
+        assert_eq!(parsed.serialize_preserving(), input);
+        parsed.lines[0].set_ip("10.0.0.2").unwrap();
+        assert_eq!(
+            parsed.serialize_preserving(),
+            "  # doc\r\n#\tmore\n10.0.0.2 db\r\n# trailing"
+        );
+        parsed.detach_leading_comments();
+        assert_eq!(
+            parsed.serialize_preserving(),
+            "  # doc\r\n#\tmore\n10.0.0.2 db\r\n# trailing"
+        );
+    }
+    #[test]
+    fn commenting_out_entry_keeps_leading_comments() {
+        let mut parsed = HostsFile::from_str("# web server\n10.0.0.2 web\n").unwrap();
+        parsed.attach_leading_comments();
+        parsed.comment_out_host("web");
+        assert_eq!(
+            parsed.lines[0].comment(),
+            Some("# 10.0.0.2 web".to_string())
+        );
+        assert_eq!(parsed.serialize(), "# web server\n# 10.0.0.2 web\n");
+    }
+    #[test]
+    fn removing_entry_removes_leading_comments() {
+        let mut parsed = HostsFile::from_str("# web server\n10.0.0.2 web\n10.0.0.1 db\n").unwrap();
+        parsed.attach_leading_comments();
+        parsed.remove_host("web");
+        assert_eq!(parsed.serialize(), "10.0.0.1 db\n");
+    }
+
+    #[test]
+    fn entries_with_comment() {
+        let parsed = HostsFile::from_str(
+            "# documented below\n127.0.0.1 localhost # loopback\n10.0.0.1 db\n10.0.0.2 web api # frontend\n",
+        )
+        .unwrap();
+        let entries = parsed.entries_with_comment();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].hosts, vec!["localhost"]);
+        assert_eq!(entries[1].ip, "10.0.0.2");
+        assert_eq!(entries[1].comment, Some("# frontend".to_string()));
+    }
+    #[test]
+    fn entries_by_tag() {
+        let parsed = HostsFile::from_str(
+            "10.0.0.1 db # env:staging owner:ops\n10.0.0.2 web # env:prod\n10.0.0.3 cache # env:staging\n10.0.0.4 misc # untagged\n",
+        )
+        .unwrap();
+        let groups = parsed.entries_by_tag("env");
+        assert_eq!(groups.len(), 2);
+        let staging: Vec<&str> = groups["staging"]
+            .iter()
+            .map(|e| e.hosts[0].as_str())
+            .collect();
+        assert_eq!(staging, vec!["db", "cache"]);
+        assert_eq!(groups["prod"][0].ip, "10.0.0.2");
+        assert_eq!(parsed.entries_by_tag("owner")["ops"].len(), 1);
+        assert!(parsed.entries_by_tag("missing").is_empty());
     }
 }